@@ -1,14 +1,405 @@
-enum List {
-    Cons(i32, Box<List>),
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
+use std::ptr;
+
+use List::{Cons, Nil};
+
+#[derive(Default)]
+pub enum List<T> {
+    Cons(T, Box<List<T>>),
+    #[default]
     Nil,
 }
 
+impl<T> List<T> {
+    pub fn new() -> List<T> {
+        Nil
+    }
+
+    pub fn push_front(&mut self, value: T) {
+        // Take the current list out of `self` so it can become the tail of the new node.
+        let rest = mem::replace(self, Nil);
+        *self = Cons(value, Box::new(rest));
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let (value, rest) = mem::replace(self, Nil).into_parts()?;
+        *self = *rest;
+        Some(value)
+    }
+
+    pub fn peek(&self) -> Option<&T> {
+        match self {
+            Cons(value, _) => Some(value),
+            Nil => None,
+        }
+    }
+
+    pub fn peek_mut(&mut self) -> Option<&mut T> {
+        match self {
+            Cons(value, _) => Some(value),
+            Nil => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Nil)
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { next: self }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut { next: Some(self) }
+    }
+
+    /// Reverses the list in place, reusing the existing boxes.
+    pub fn reverse(&mut self) {
+        let mut reversed = Nil;
+        let mut current = mem::replace(self, Nil);
+
+        while let Some((value, mut rest)) = current.into_parts() {
+            current = mem::replace(&mut *rest, reversed);
+            reversed = Cons(value, rest);
+        }
+
+        *self = reversed;
+    }
+
+    // `List` implements `Drop`, so its fields cannot be moved out with a plain `match`.
+    fn into_parts(self) -> Option<(T, Box<List<T>>)> {
+        let list = ManuallyDrop::new(self);
+        match &*list {
+            // SAFETY: `list` is never dropped, so each field is read out exactly once.
+            Cons(value, rest) => unsafe { Some((ptr::read(value), ptr::read(rest))) },
+            Nil => None,
+        }
+    }
+
+    // Walk to the trailing `Nil` so new elements can be appended in order.
+    fn tail_mut(&mut self) -> &mut List<T> {
+        let mut cursor = self;
+        while let Cons(_, rest) = cursor {
+            cursor = rest;
+        }
+        cursor
+    }
+}
+
+// The derived drop glue would recurse once per node, so a long list overflows the stack.
+// Unlink the nodes one at a time instead; each box is dropped with a `Nil` tail.
+impl<T> Drop for List<T> {
+    fn drop(&mut self) {
+        let mut current = mem::replace(self, Nil);
+        while let Some((_, mut rest)) = current.into_parts() {
+            current = mem::replace(&mut *rest, Nil);
+        }
+    }
+}
+
+impl<T: Clone> Clone for List<T> {
+    fn clone(&self) -> List<T> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<T: PartialEq> PartialEq for List<T> {
+    fn eq(&self, other: &List<T>) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Eq> Eq for List<T> {}
+
+pub struct Iter<'a, T> {
+    next: &'a List<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next {
+            Cons(value, rest) => {
+                self.next = rest;
+                Some(value)
+            }
+            Nil => None,
+        }
+    }
+}
+
+pub struct IterMut<'a, T> {
+    next: Option<&'a mut List<T>>,
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next.take()? {
+            Cons(value, rest) => {
+                self.next = Some(rest);
+                Some(value)
+            }
+            Nil => None,
+        }
+    }
+}
+
+pub struct IntoIter<T>(List<T>);
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.pop_front()
+    }
+}
+
+impl<T> IntoIterator for List<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter(self)
+    }
+}
+
+impl<'a, T> IntoIterator for &'a List<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut List<T> {
+    type Item = &'a mut T;
+    type IntoIter = IterMut<'a, T>;
+
+    fn into_iter(self) -> IterMut<'a, T> {
+        self.iter_mut()
+    }
+}
+
+impl<T> FromIterator<T> for List<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> List<T> {
+        let mut list = Nil;
+        list.extend(iter);
+        list
+    }
+}
+
+// Extending appends to the back, so `collect` keeps the iterator order.
+impl<T> Extend<T> for List<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let mut cursor = self.tail_mut();
+        for value in iter {
+            *cursor = Cons(value, Box::new(Nil));
+            if let Cons(_, rest) = cursor {
+                cursor = rest;
+            }
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(")?;
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{value}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(")?;
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{value:?}")?;
+        }
+        write!(f, ")")
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::boxt::List::{Cons, Nil};
+    use crate::boxt::List::{self, Cons, Nil};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Bumps a shared counter when dropped, so tests can see exactly when payloads go away.
+    struct DropCounter(Rc<Cell<usize>>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
 
     #[test]
     fn run() {
         let _list = Cons(1, Box::new(Cons(2, Box::new(Cons(3, Box::new(Nil))))));
     }
+
+    #[test]
+    fn push_and_pop_front() {
+        let mut list = List::new();
+        assert!(list.is_empty());
+        assert_eq!(list.pop_front(), None);
+
+        list.push_front(3);
+        list.push_front(2);
+        list.push_front(1);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.peek(), Some(&1));
+
+        assert_eq!(list.pop_front(), Some(1));
+        assert_eq!(list.pop_front(), Some(2));
+        assert_eq!(list.pop_front(), Some(3));
+        assert_eq!(list.pop_front(), None);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn peek_mut_updates_head() {
+        let mut list: List<i32> = [1, 2].into_iter().collect();
+        if let Some(head) = list.peek_mut() {
+            *head = 10;
+        }
+        assert_eq!(list.peek(), Some(&10));
+    }
+
+    #[test]
+    fn collect_matches_nested_constructors() {
+        let list: List<i32> = (1..=3).collect();
+        let nested = Cons(1, Box::new(Cons(2, Box::new(Cons(3, Box::new(Nil))))));
+        assert_eq!(list, nested);
+    }
+
+    #[test]
+    fn iterators() {
+        let mut list: List<i32> = (1..=3).collect();
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        for value in &mut list {
+            *value *= 10;
+        }
+        assert_eq!((&list).into_iter().sum::<i32>(), 60);
+        assert_eq!(list.into_iter().collect::<Vec<_>>(), vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn extend_appends_to_back() {
+        let mut list: List<i32> = (1..=2).collect();
+        list.extend(vec![3, 4]);
+        list.push_front(0);
+        assert_eq!(
+            list.iter().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn reverse() {
+        let mut list: List<i32> = (1..=4).collect();
+        list.reverse();
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), vec![4, 3, 2, 1]);
+
+        let mut empty = List::<i32>::new();
+        empty.reverse();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn clone_and_eq() {
+        let list: List<i32> = (1..=3).collect();
+        let copy = list.clone();
+        assert_eq!(list, copy);
+
+        let shorter: List<i32> = (1..=2).collect();
+        assert_ne!(list, shorter);
+    }
+
+    #[test]
+    fn display_and_debug() {
+        let list: List<i32> = (1..=3).collect();
+        assert_eq!(format!("{list}"), "(1 2 3)");
+        assert_eq!(format!("{list:?}"), "(1 2 3)");
+        assert_eq!(format!("{}", List::<i32>::new()), "()");
+    }
+
+    #[test]
+    fn string_payloads() {
+        let mut list: List<String> = ["b", "c"].iter().map(|s| s.to_string()).collect();
+        list.push_front(String::from("a"));
+        for value in list.iter_mut() {
+            value.push('!');
+        }
+
+        assert_eq!(format!("{list}"), "(a! b! c!)");
+        assert_eq!(format!("{list:?}"), r#"("a!" "b!" "c!")"#);
+        assert_eq!(list.clone(), list);
+        assert_eq!(list.pop_front(), Some(String::from("a!")));
+        assert_eq!(list.into_iter().collect::<Vec<_>>(), vec!["b!", "c!"]);
+    }
+
+    #[test]
+    fn payloads_are_dropped_exactly_once() {
+        let drops = Rc::new(Cell::new(0));
+        let mut list: List<DropCounter> = (0..5).map(|_| DropCounter(Rc::clone(&drops))).collect();
+
+        let head = list.pop_front();
+        assert_eq!(drops.get(), 0);
+        drop(head);
+        assert_eq!(drops.get(), 1);
+
+        list.reverse();
+        assert_eq!(drops.get(), 1);
+
+        drop(list);
+        assert_eq!(drops.get(), 5);
+    }
+
+    const DEEP: i32 = 1_000_000;
+
+    #[test]
+    fn deep_list_is_stack_safe() {
+        let list: List<i32> = (0..DEEP).collect();
+        assert_eq!(list.len(), DEEP as usize);
+
+        let copy = list.clone();
+        assert_eq!(list, copy);
+        assert!(format!("{copy:?}").ends_with(" 999999)"));
+
+        let mut reversed = copy;
+        reversed.reverse();
+        assert_eq!(reversed.peek(), Some(&(DEEP - 1)));
+
+        drop(list);
+        drop(reversed);
+    }
+
+    #[test]
+    fn deep_list_built_by_push_front_drops() {
+        let mut list = List::new();
+        for i in 0..DEEP {
+            list.push_front(i);
+        }
+        drop(list);
+    }
 }
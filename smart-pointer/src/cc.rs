@@ -0,0 +1,686 @@
+//! `Cc<T>`: a reference-counted pointer that can also free cycles.
+//!
+//! It works like `Rc<T>`. When a strong count drops but doesn't reach zero, the
+//! allocation may have become garbage held up only by a cycle, so it is buffered as a
+//! possible root. `collect_cycles` runs the synchronous trial-deletion pass from Bacon
+//! and Rajan's "Concurrent Cycle Collection in Reference Counted Systems" over those
+//! roots: subtract the references coming from inside the candidate subgraph, and
+//! whatever ends up at zero is only kept alive by itself.
+
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+/// Reports the `Cc` pointers a value owns, so the collector can follow them.
+///
+/// # Safety
+///
+/// `trace` must visit each `Cc` the value owns exactly once, and no other `Cc`.
+/// The collector subtracts one from a count for every visit, so an extra visit,
+/// or one to a `Cc` owned by someone else, makes a live value look like garbage
+/// and it gets freed while still in use. A missed `Cc` is only a leak.
+///
+/// The values in a collected cycle are dropped one after another, so a `Drop` impl
+/// that dereferences another `Cc` of the same cycle panics instead of reading a
+/// value that is already gone. While the collector drops garbage, no `Cc`
+/// decrements its count at all, so a `Cc` created or dropped inside such a `Drop`
+/// impl is never freed.
+pub unsafe trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+pub struct Tracer {
+    found: Vec<Erased>,
+    busy: bool,
+}
+
+impl Tracer {
+    pub fn visit<T: Trace + 'static>(&mut self, cc: &Cc<T>) {
+        self.found.push(cc.ptr);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Color {
+    /// In use, or already freed.
+    Black,
+    /// Member of the subgraph under trial deletion.
+    Gray,
+    /// Garbage: only referenced from inside a cycle.
+    White,
+    /// A possible cycle root.
+    Purple,
+    /// The last `Cc` is dropping the value; that `Cc` frees the box afterwards.
+    Dropping,
+}
+
+struct CcBox<T: ?Sized> {
+    strong: Cell<usize>,
+    color: Cell<Color>,
+    buffered: Cell<bool>,
+    // Set by the collector before it drops the value of a garbage box.
+    collected: Cell<bool>,
+    value: UnsafeCell<ManuallyDrop<T>>,
+}
+
+type Erased = NonNull<CcBox<dyn Trace>>;
+
+const DEFAULT_THRESHOLD: usize = 128;
+
+struct Roots(Vec<Erased>);
+
+// At thread exit, free the buffered boxes whose value is already gone. Anything still
+// alive at that point leaks, like an `Rc` cycle would.
+impl Drop for Roots {
+    fn drop(&mut self) {
+        for &root in &self.0 {
+            let inner = header(root);
+            if inner.color.get() == Color::Black && inner.strong.get() == 0 {
+                // SAFETY: its value was dropped and nothing points at it.
+                unsafe { free(root) };
+            }
+        }
+    }
+}
+
+thread_local! {
+    static ROOTS: RefCell<Roots> = const { RefCell::new(Roots(Vec::new())) };
+    static THRESHOLD: Cell<usize> = const { Cell::new(DEFAULT_THRESHOLD) };
+    // Set while the collector drops garbage values. Every `Cc` decrement is skipped
+    // then, because edges inside the garbage were already subtracted, and that
+    // includes `Cc`s the `Drop` impls create or drop themselves, which leak.
+    static FREEING: Cell<bool> = const { Cell::new(false) };
+}
+
+pub struct Cc<T: Trace + 'static> {
+    ptr: NonNull<CcBox<T>>,
+    _marker: PhantomData<CcBox<T>>,
+}
+
+impl<T: Trace + 'static> Cc<T> {
+    /// Allocates `value`, running a collection first when enough possible roots
+    /// have piled up.
+    pub fn new(value: T) -> Cc<T> {
+        if possible_roots() >= THRESHOLD.get() {
+            collect_cycles();
+        }
+
+        let inner = Box::new(CcBox {
+            strong: Cell::new(1),
+            color: Cell::new(Color::Black),
+            buffered: Cell::new(false),
+            collected: Cell::new(false),
+            value: UnsafeCell::new(ManuallyDrop::new(value)),
+        });
+        Cc {
+            ptr: NonNull::from(Box::leak(inner)),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn strong_count(this: &Cc<T>) -> usize {
+        this.inner().strong.get()
+    }
+
+    pub fn ptr_eq(this: &Cc<T>, other: &Cc<T>) -> bool {
+        this.ptr == other.ptr
+    }
+
+    fn inner(&self) -> &CcBox<T> {
+        // SAFETY: the box stays allocated while any `Cc` points at it.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: Trace + 'static> Clone for Cc<T> {
+    fn clone(&self) -> Cc<T> {
+        let inner = self.inner();
+        inner.strong.set(inner.strong.get() + 1);
+        inner.color.set(Color::Black);
+        Cc {
+            ptr: self.ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Trace + 'static> Deref for Cc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        let inner = self.inner();
+        // Only a `Drop` impl running inside a collection can still hold such a `Cc`.
+        assert!(
+            !inner.collected.get(),
+            "Cc dereferenced after its cycle was collected"
+        );
+        // SAFETY: the value is only dropped once the strong count reaches zero, or
+        // by the collector once `collected` is set.
+        unsafe { &*inner.value.get() }
+    }
+}
+
+impl<T: Trace + 'static> Drop for Cc<T> {
+    fn drop(&mut self) {
+        // Edges out of a collected cycle were already subtracted by trial deletion.
+        if FREEING.get() {
+            return;
+        }
+
+        let inner = self.inner();
+        inner.strong.set(inner.strong.get() - 1);
+
+        if inner.strong.get() == 0 {
+            // The value's `Drop` may run a collection, which must leave this box alone.
+            inner.color.set(Color::Dropping);
+            // SAFETY: this was the last strong reference, so nothing else reads the value.
+            unsafe { ManuallyDrop::drop(&mut *inner.value.get()) };
+            inner.color.set(Color::Black);
+            // A buffered box is still listed as a root; the collector frees it later.
+            if !inner.buffered.get() {
+                // SAFETY: not buffered and no strong references left.
+                unsafe { free(self.ptr) };
+            }
+        } else if inner.color.get() != Color::Purple {
+            inner.color.set(Color::Purple);
+            if !inner.buffered.get() {
+                inner.buffered.set(true);
+                let ptr: Erased = self.ptr;
+                // During thread teardown the buffer may already be gone; then it just leaks.
+                let _ = ROOTS.try_with(|roots| roots.borrow_mut().0.push(ptr));
+            }
+        }
+    }
+}
+
+impl<T: Trace + fmt::Debug + 'static> fmt::Debug for Cc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+// SAFETY: a `Cc` owns only itself.
+unsafe impl<T: Trace + 'static> Trace for Cc<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.visit(self);
+    }
+}
+
+/// Sets how many possible roots may pile up before `Cc::new` collects on its own.
+pub fn set_collect_threshold(threshold: usize) {
+    THRESHOLD.set(threshold);
+}
+
+/// Number of allocations currently buffered as possible cycle roots.
+pub fn possible_roots() -> usize {
+    ROOTS.with_borrow(|roots| roots.0.len())
+}
+
+/// Frees every garbage cycle reachable from the buffered roots and returns how
+/// many allocations were freed.
+pub fn collect_cycles() -> usize {
+    // A `Drop` impl running inside a collection can't start another one.
+    if FREEING.get() {
+        return 0;
+    }
+    let roots = ROOTS.with_borrow_mut(|roots| mem::take(&mut roots.0));
+
+    let mut candidates = Vec::new();
+    for root in roots {
+        let inner = header(root);
+        if inner.color.get() == Color::Purple {
+            mark_gray(root);
+            candidates.push(root);
+        } else if inner.color.get() == Color::Dropping {
+            // Collecting from inside its value's `Drop`; the dropping `Cc` frees it
+            // once it sees the box is no longer buffered.
+            inner.buffered.set(false);
+        } else {
+            // Either revived, or already grayed through an earlier root.
+            inner.buffered.set(false);
+            if inner.color.get() == Color::Black && inner.strong.get() == 0 {
+                // SAFETY: its value was dropped while it sat in the buffer.
+                unsafe { free(root) };
+            }
+        }
+    }
+
+    for &root in &candidates {
+        scan(root);
+    }
+
+    let mut garbage = Vec::new();
+    for root in candidates {
+        header(root).buffered.set(false);
+        collect_white(root, &mut garbage);
+    }
+
+    // Mark every value first, so a `Drop` impl that reaches into the rest of the
+    // cycle panics whatever order the values are dropped in.
+    for &node in &garbage {
+        header(node).collected.set(true);
+    }
+    {
+        let _freeing = FreeingGuard::enter();
+        for &node in &garbage {
+            // SAFETY: white nodes are unreachable from outside the cycle.
+            unsafe { ManuallyDrop::drop(&mut *header(node).value.get()) };
+        }
+    }
+    for &node in &garbage {
+        // SAFETY: every value is dropped and no `Cc` outside the garbage points here.
+        unsafe { free(node) };
+    }
+    garbage.len()
+}
+
+// Trial deletion: remove the counts contributed by every edge inside the subgraph.
+fn mark_gray(root: Erased) {
+    if header(root).color.get() == Color::Gray {
+        return;
+    }
+    header(root).color.set(Color::Gray);
+
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        for child in children(node).unwrap_or_default() {
+            let inner = header(child);
+            inner.strong.set(inner.strong.get() - 1);
+            if inner.color.get() != Color::Gray {
+                inner.color.set(Color::Gray);
+                stack.push(child);
+            }
+        }
+    }
+}
+
+// Anything still referenced from outside is live, along with everything it reaches.
+fn scan(root: Erased) {
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        let inner = header(node);
+        if inner.color.get() != Color::Gray {
+            continue;
+        }
+        match children(node) {
+            Some(children) if inner.strong.get() == 0 => {
+                inner.color.set(Color::White);
+                stack.extend(children);
+            }
+            // A `RefCell` inside is mutably borrowed, so someone holds a live reference.
+            _ => scan_black(node),
+        }
+    }
+}
+
+// Undo the trial deletion for a live node and everything reachable from it.
+fn scan_black(root: Erased) {
+    header(root).color.set(Color::Black);
+
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        for child in children(node).unwrap_or_default() {
+            let inner = header(child);
+            inner.strong.set(inner.strong.get() + 1);
+            if inner.color.get() != Color::Black {
+                inner.color.set(Color::Black);
+                stack.push(child);
+            }
+        }
+    }
+}
+
+fn collect_white(root: Erased, garbage: &mut Vec<Erased>) {
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        let inner = header(node);
+        if inner.color.get() == Color::White && !inner.buffered.get() {
+            inner.color.set(Color::Black);
+            garbage.push(node);
+            stack.extend(children(node).unwrap_or_default());
+        }
+    }
+}
+
+// `None` when a `RefCell` on the way was mutably borrowed and couldn't be traced.
+// Nothing else runs during a collection, so the answer is the same in every phase.
+fn children(node: Erased) -> Option<Vec<Erased>> {
+    let mut tracer = Tracer {
+        found: Vec::new(),
+        busy: false,
+    };
+    // SAFETY: only nodes with a live value are traced.
+    unsafe { &*header(node).value.get() }.trace(&mut tracer);
+    (!tracer.busy).then_some(tracer.found)
+}
+
+fn header<'a>(node: Erased) -> &'a CcBox<dyn Trace> {
+    // SAFETY: the collector only handles boxes that are still allocated.
+    unsafe { node.as_ref() }
+}
+
+// SAFETY: the caller guarantees the value was dropped and nothing points at `node`.
+unsafe fn free(node: Erased) {
+    drop(unsafe { Box::from_raw(node.as_ptr()) });
+}
+
+struct FreeingGuard;
+
+impl FreeingGuard {
+    fn enter() -> FreeingGuard {
+        FREEING.set(true);
+        FreeingGuard
+    }
+}
+
+impl Drop for FreeingGuard {
+    fn drop(&mut self) {
+        FREEING.set(false);
+    }
+}
+
+// SAFETY: forwards to the one value inside.
+unsafe impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.try_borrow() {
+            Ok(value) => value.trace(tracer),
+            Err(_) => tracer.busy = true,
+        }
+    }
+}
+
+// SAFETY: forwards to the value, if any.
+unsafe impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(value) = self {
+            value.trace(tracer);
+        }
+    }
+}
+
+// SAFETY: forwards to each element once.
+unsafe impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self {
+            value.trace(tracer);
+        }
+    }
+}
+
+// SAFETY: forwards to the boxed value.
+unsafe impl<T: Trace + ?Sized> Trace for Box<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        (**self).trace(tracer);
+    }
+}
+
+// Plain data never owns a `Cc`, so visiting nothing is sound.
+macro_rules! trace_nothing {
+    ($($t:ty),*) => {
+        $(
+            unsafe impl Trace for $t {
+                fn trace(&self, _: &mut Tracer) {}
+            }
+        )*
+    };
+}
+
+trace_nothing!(
+    (),
+    bool,
+    char,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    f32,
+    f64,
+    String,
+    &'static str
+);
+
+#[cfg(test)]
+mod tests {
+    use super::{Cc, Trace, Tracer, collect_cycles, possible_roots, set_collect_threshold};
+    use crate::test_util::{LeakTracker, Tracked};
+    use List::{Cons, Nil};
+    use std::cell::RefCell;
+
+    // The `reference_cycle` list, with `Cc` in place of `Rc`.
+    enum List {
+        Cons(Tracked<i32>, RefCell<Cc<List>>),
+        Nil,
+    }
+
+    impl List {
+        fn tail(&self) -> Option<&RefCell<Cc<List>>> {
+            match self {
+                Cons(_, item) => Some(item),
+                Nil => None,
+            }
+        }
+    }
+
+    // SAFETY: visits the one tail a `Cons` owns.
+    unsafe impl Trace for List {
+        fn trace(&self, tracer: &mut Tracer) {
+            if let Cons(_, next) = self {
+                next.trace(tracer);
+            }
+        }
+    }
+
+    fn cons(tracker: &LeakTracker, value: i32, next: &Cc<List>) -> Cc<List> {
+        Cc::new(Cons(tracker.track(value), RefCell::new(next.clone())))
+    }
+
+    #[test]
+    fn run() {
+        let tracker = LeakTracker::new();
+        {
+            let a = Cc::new(Cons(tracker.track(5), RefCell::new(Cc::new(Nil))));
+            let b = cons(&tracker, 10, &a);
+            assert_eq!(Cc::strong_count(&a), 2);
+
+            *a.tail().unwrap().borrow_mut() = b.clone();
+            assert_eq!(Cc::strong_count(&a), 2);
+            assert_eq!(Cc::strong_count(&b), 2);
+        }
+
+        // Plain reference counting can't free `a <-> b`.
+        assert_eq!(tracker.dropped(), 0);
+        assert_eq!(collect_cycles(), 2);
+        assert_eq!(tracker.dropped(), 2);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn acyclic_values_are_freed_without_collecting() {
+        let tracker = LeakTracker::new();
+        let nil = Cc::new(Nil);
+        let a = cons(&tracker, 1, &nil);
+        let b = cons(&tracker, 2, &a);
+        drop(a);
+        drop(b);
+
+        assert_eq!(tracker.dropped(), 2);
+        assert_eq!(Cc::strong_count(&nil), 1);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn cycles_with_outside_references_survive() {
+        let tracker = LeakTracker::new();
+        let nil = Cc::new(Nil);
+        let a = cons(&tracker, 1, &nil);
+        let b = cons(&tracker, 2, &a);
+        *a.tail().unwrap().borrow_mut() = b.clone();
+        drop(b);
+
+        assert_eq!(collect_cycles(), 0);
+        assert_eq!(tracker.dropped(), 0);
+        assert_eq!(Cc::strong_count(&a), 2);
+        {
+            let link = a.tail().unwrap().borrow();
+            let Cons(value, _) = &**link else {
+                panic!("a's tail is a Cons");
+            };
+            assert_eq!(**value, 2);
+        }
+
+        drop(a);
+        assert_eq!(collect_cycles(), 2);
+        assert_eq!(Cc::strong_count(&nil), 1);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn live_values_referenced_from_a_cycle_keep_their_count() {
+        let tracker = LeakTracker::new();
+        let nil = Cc::new(Nil);
+        let shared = cons(&tracker, 0, &nil);
+
+        let a = cons(&tracker, 1, &shared);
+        let b = cons(&tracker, 2, &a);
+        let c = Cc::new(Cons(tracker.track(3), RefCell::new(shared.clone())));
+        *a.tail().unwrap().borrow_mut() = b.clone();
+        *shared.tail().unwrap().borrow_mut() = c.clone();
+        drop((a, b, c));
+
+        // Only `a <-> b` is garbage; `shared -> c` stays because `shared` is still held.
+        assert_eq!(collect_cycles(), 2);
+        assert_eq!(tracker.dropped(), 2);
+        assert_eq!(Cc::strong_count(&shared), 2);
+
+        drop(shared);
+        assert_eq!(collect_cycles(), 2);
+        assert_eq!(Cc::strong_count(&nil), 1);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn borrowed_cycles_are_kept_until_released() {
+        let tracker = LeakTracker::new();
+        let a = Cc::new(Cons(tracker.track(1), RefCell::new(Cc::new(Nil))));
+        let b = cons(&tracker, 2, &a);
+        *a.tail().unwrap().borrow_mut() = b.clone();
+
+        let link = a.tail().unwrap().borrow_mut();
+        drop(b);
+        assert_eq!(collect_cycles(), 0);
+        drop(link);
+
+        drop(a);
+        assert_eq!(collect_cycles(), 2);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn collecting_from_a_drop_impl() {
+        struct Reentrant {
+            _payload: Tracked<i32>,
+        }
+
+        // SAFETY: owns no `Cc`.
+        unsafe impl Trace for Reentrant {
+            fn trace(&self, _: &mut Tracer) {}
+        }
+
+        impl Drop for Reentrant {
+            fn drop(&mut self) {
+                collect_cycles();
+            }
+        }
+
+        let tracker = LeakTracker::new();
+        let a = Cc::new(Reentrant {
+            _payload: tracker.track(1),
+        });
+        drop(a.clone());
+        assert_eq!(possible_roots(), 1);
+
+        // The box is still buffered while its value drops, and the collection that
+        // starts from that `Drop` must not free it out from under the `Cc`.
+        drop(a);
+        assert_eq!(possible_roots(), 0);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn threshold_triggers_collection() {
+        let tracker = LeakTracker::new();
+        set_collect_threshold(4);
+        for i in 0..20 {
+            let a = Cc::new(Cons(tracker.track(i), RefCell::new(Cc::new(Nil))));
+            let b = cons(&tracker, i, &a);
+            *a.tail().unwrap().borrow_mut() = b;
+        }
+
+        // Each iteration leaves one root behind; every fourth `Cc::new` collects them.
+        assert_eq!(tracker.dropped(), 32);
+        assert_eq!(possible_roots(), 4);
+        assert_eq!(collect_cycles(), 8);
+        assert_eq!(tracker.dropped(), 40);
+        set_collect_threshold(super::DEFAULT_THRESHOLD);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn drop_impls_cannot_read_the_rest_of_a_collected_cycle() {
+        struct Peer {
+            other: RefCell<Option<Cc<Peer>>>,
+            seen: Cc<RefCell<Vec<String>>>,
+        }
+
+        // SAFETY: visits its peer and the log, each once.
+        unsafe impl Trace for Peer {
+            fn trace(&self, tracer: &mut Tracer) {
+                self.other.trace(tracer);
+                self.seen.trace(tracer);
+            }
+        }
+
+        impl Drop for Peer {
+            fn drop(&mut self) {
+                let read = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    self.other
+                        .borrow()
+                        .as_ref()
+                        .map(|other| other.seen.borrow().len())
+                }));
+                let outcome = match read {
+                    Ok(_) => "read its peer",
+                    Err(_) => "panicked",
+                };
+                // The log outlives the cycle, so it can still be written to.
+                self.seen.borrow_mut().push(String::from(outcome));
+            }
+        }
+
+        let seen = Cc::new(RefCell::new(vec![]));
+        let a = Cc::new(Peer {
+            other: RefCell::new(None),
+            seen: seen.clone(),
+        });
+        let b = Cc::new(Peer {
+            other: RefCell::new(Some(a.clone())),
+            seen: seen.clone(),
+        });
+        *a.other.borrow_mut() = Some(b.clone());
+        drop((a, b));
+
+        assert_eq!(collect_cycles(), 2);
+        assert_eq!(*seen.borrow(), ["panicked", "panicked"]);
+    }
+}
@@ -0,0 +1,224 @@
+//! Shared Graphviz DOT output for the `to_dot` exporters.
+//!
+//! Each allocation becomes one node, labeled with its value and its strong and weak
+//! counts. Strong references are solid edges, weak ones dashed. Nodes and edges that
+//! sit on a cycle of strong references, the kind that leaks, are drawn in red.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Edge {
+    Strong,
+    Weak,
+}
+
+#[derive(Default)]
+pub(crate) struct DotGraph {
+    ids: HashMap<*const (), usize>,
+    labels: Vec<String>,
+    edges: Vec<(usize, usize, Edge)>,
+}
+
+impl DotGraph {
+    pub(crate) fn new() -> DotGraph {
+        DotGraph::default()
+    }
+
+    /// The node id for the allocation at `ptr`, and whether it was seen before.
+    pub(crate) fn id<P: ?Sized>(&mut self, ptr: *const P) -> (usize, bool) {
+        let next = self.labels.len();
+        let id = *self.ids.entry(ptr.cast()).or_insert(next);
+        let seen = id != next;
+        if !seen {
+            self.labels.push(String::new());
+        }
+        (id, seen)
+    }
+
+    pub(crate) fn label(&mut self, id: usize, value: &str, strong: usize, weak: usize) {
+        self.labels[id] = format!("{}\\nstrong: {strong}, weak: {weak}", escape(value));
+    }
+
+    pub(crate) fn edge(&mut self, from: usize, to: usize, kind: Edge) {
+        self.edges.push((from, to, kind));
+    }
+
+    pub(crate) fn finish(self) -> String {
+        let component = self.strong_components();
+        let mut on_cycle = vec![false; self.labels.len()];
+        let mut sizes = HashMap::new();
+        for &c in &component {
+            *sizes.entry(c).or_insert(0) += 1;
+        }
+        for (id, c) in component.iter().enumerate() {
+            on_cycle[id] = sizes[c] > 1;
+        }
+        for &(from, to, kind) in &self.edges {
+            if kind == Edge::Strong && from == to {
+                on_cycle[from] = true;
+            }
+        }
+
+        let mut out = String::from("digraph {\n    node [shape=box];\n");
+        for (id, label) in self.labels.iter().enumerate() {
+            let color = if on_cycle[id] { ", color=red" } else { "" };
+            writeln!(out, "    n{id} [label=\"{label}\"{color}];").unwrap();
+        }
+        for &(from, to, kind) in &self.edges {
+            let mut attrs = vec![];
+            if kind == Edge::Weak {
+                attrs.push("style=dashed");
+            } else if component[from] == component[to] && on_cycle[from] {
+                attrs.push("color=red");
+            }
+            let attrs = if attrs.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", attrs.join(", "))
+            };
+            writeln!(out, "    n{from} -> n{to}{attrs};").unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    // Kosaraju over the strong edges: a component with more than one node is a cycle.
+    fn strong_components(&self) -> Vec<usize> {
+        let n = self.labels.len();
+        let mut forward = vec![vec![]; n];
+        let mut backward = vec![vec![]; n];
+        for &(from, to, kind) in &self.edges {
+            if kind == Edge::Strong {
+                forward[from].push(to);
+                backward[to].push(from);
+            }
+        }
+
+        // Finish order of a depth-first search, without recursion.
+        let mut order = Vec::with_capacity(n);
+        let mut visited = vec![false; n];
+        for start in 0..n {
+            if visited[start] {
+                continue;
+            }
+            visited[start] = true;
+            let mut stack = vec![(start, 0)];
+            while let Some((node, next)) = stack.pop() {
+                if let Some(&child) = forward[node].get(next) {
+                    stack.push((node, next + 1));
+                    if !visited[child] {
+                        visited[child] = true;
+                        stack.push((child, 0));
+                    }
+                } else {
+                    order.push(node);
+                }
+            }
+        }
+
+        let mut component = vec![usize::MAX; n];
+        for (c, &start) in order.iter().rev().enumerate() {
+            if component[start] != usize::MAX {
+                continue;
+            }
+            component[start] = c;
+            let mut stack = vec![start];
+            while let Some(node) = stack.pop() {
+                for &prev in &backward[node] {
+                    if component[prev] == usize::MAX {
+                        component[prev] = c;
+                        stack.push(prev);
+                    }
+                }
+            }
+        }
+        component
+    }
+}
+
+/// Draws the linked lists starting at `roots`, with a strong edge from each node to
+/// the next, following each list until its end or a node already drawn. `visit`
+/// labels a node, can add more of the node's own content to the graph, and returns
+/// the node after it, or `None` at the end of the list.
+pub(crate) fn list_to_dot<L, F>(roots: &[&Rc<L>], mut visit: F) -> String
+where
+    F: FnMut(&mut DotGraph, usize, &L) -> (String, Option<Rc<L>>),
+{
+    let mut graph = DotGraph::new();
+    for &root in roots {
+        let mut prev = None;
+        let mut current = Rc::clone(root);
+        loop {
+            let (id, seen) = graph.id(Rc::as_ptr(&current));
+            if let Some(prev) = prev {
+                graph.edge(prev, id, Edge::Strong);
+            }
+            if seen {
+                break;
+            }
+            // Leave out the clone in `current`, which only exists while we walk.
+            let strong = Rc::strong_count(&current) - 1;
+            let weak = Rc::weak_count(&current);
+            let (label, next) = visit(&mut graph, id, &current);
+            graph.label(id, &label, strong, weak);
+            match next {
+                Some(next) => {
+                    prev = Some(id);
+                    current = next;
+                }
+                None => break,
+            }
+        }
+    }
+    graph.finish()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DotGraph, Edge};
+
+    #[test]
+    fn marks_only_strong_cycles() {
+        let values = [1u8, 2, 3, 4];
+        let mut graph = DotGraph::new();
+        let (a, _) = graph.id(&values[0]);
+        let (b, _) = graph.id(&values[1]);
+        let (c, _) = graph.id(&values[2]);
+        let (d, _) = graph.id(&values[3]);
+        assert_eq!(graph.id(&values[1]), (b, true));
+        graph.label(a, "a", 1, 0);
+        graph.label(b, "\"b\"", 2, 1);
+        graph.label(c, "c", 1, 0);
+        graph.label(d, "d", 1, 0);
+        graph.edge(a, b, Edge::Strong);
+        graph.edge(b, a, Edge::Strong);
+        graph.edge(b, c, Edge::Strong);
+        graph.edge(c, b, Edge::Weak);
+
+        assert_eq!(
+            graph.finish(),
+            concat!(
+                "digraph {\n",
+                "    node [shape=box];\n",
+                "    n0 [label=\"a\\nstrong: 1, weak: 0\", color=red];\n",
+                "    n1 [label=\"\\\"b\\\"\\nstrong: 2, weak: 1\", color=red];\n",
+                "    n2 [label=\"c\\nstrong: 1, weak: 0\"];\n",
+                "    n3 [label=\"d\\nstrong: 1, weak: 0\"];\n",
+                "    n0 -> n1 [color=red];\n",
+                "    n1 -> n0 [color=red];\n",
+                "    n1 -> n2;\n",
+                "    n2 -> n1 [style=dashed];\n",
+                "}\n",
+            )
+        );
+    }
+}
@@ -1,6 +1,21 @@
 pub mod boxt;
+pub mod cc;
+mod dot;
 pub mod rct;
 pub mod refcellt;
+pub mod refcellt_async;
+pub mod refcellt_messengers;
+pub mod refcellt_persist;
+pub mod refcellt_sync;
+pub mod refcellt_tracked;
+pub mod refcellt_tree;
+pub mod refcellt_window;
 pub mod refcellt_with_rct;
 pub mod reference_cycle;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+mod tree_walk;
 pub mod weakt;
+pub mod weakt_events;
+pub mod weakt_sync;
+pub mod weakt_text;
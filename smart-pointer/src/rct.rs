@@ -1,28 +1,521 @@
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
+use std::ptr;
 use std::rc::Rc;
 
-enum List {
-    Cons(i32, Rc<List>),
+use crate::dot;
+use List::{Cons, Nil};
+
+#[derive(Clone)]
+pub enum List<T> {
+    Cons(T, Rc<List<T>>),
     Nil,
 }
 
+impl<T> List<T> {
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { next: self }
+    }
+
+    // `List` implements `Drop`, so its fields cannot be moved out with a plain `match`.
+    fn into_parts(self) -> Option<(T, Rc<List<T>>)> {
+        let list = ManuallyDrop::new(self);
+        match &*list {
+            // SAFETY: `list` is never dropped, so each field is read out exactly once.
+            Cons(value, rest) => unsafe { Some((ptr::read(value), ptr::read(rest))) },
+            Nil => None,
+        }
+    }
+}
+
+// Only the nodes this list owns alone are freed here; once a tail is still shared
+// with another list we just release our reference and stop.
+impl<T> Drop for List<T> {
+    fn drop(&mut self) {
+        let Cons(_, rest) = self else {
+            return;
+        };
+        if Rc::strong_count(rest) > 1 {
+            return;
+        }
+
+        let mut next = mem::replace(rest, Rc::new(Nil));
+        while let Ok(node) = Rc::try_unwrap(next) {
+            match node.into_parts() {
+                Some((_, rest)) => next = rest,
+                None => break,
+            }
+        }
+    }
+}
+
+impl<T: PartialEq> PartialEq for List<T> {
+    fn eq(&self, other: &List<T>) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Eq> Eq for List<T> {}
+
+impl<T: fmt::Debug> fmt::Debug for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(")?;
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{value:?}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Graphviz DOT for the lists starting at `roots`, one node per allocation, so
+/// shared tails show up as nodes with more than one incoming edge.
+pub fn to_dot<T: fmt::Debug>(roots: &[&Rc<List<T>>]) -> String {
+    dot::list_to_dot(roots, |_, _, node| match node {
+        Cons(value, rest) => (format!("{value:?}"), Some(Rc::clone(rest))),
+        Nil => (String::from("Nil"), None),
+    })
+}
+
+pub struct Iter<'a, T> {
+    next: &'a List<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next {
+            Cons(value, rest) => {
+                self.next = rest;
+                Some(value)
+            }
+            Nil => None,
+        }
+    }
+}
+
+/// An immutable list where every operation returns a new version.
+///
+/// Versions share their common tails through `Rc`, the same way `b` and `c` share `a`
+/// in the test below; only the nodes that actually differ are allocated.
+pub struct PersistentList<T> {
+    head: Rc<List<T>>,
+}
+
+impl<T> PersistentList<T> {
+    pub fn new() -> PersistentList<T> {
+        PersistentList { head: Rc::new(Nil) }
+    }
+
+    pub fn cons(&self, value: T) -> PersistentList<T> {
+        PersistentList {
+            head: Rc::new(Cons(value, Rc::clone(&self.head))),
+        }
+    }
+
+    pub fn head(&self) -> Option<&T> {
+        match &*self.head {
+            Cons(value, _) => Some(value),
+            Nil => None,
+        }
+    }
+
+    pub fn tail(&self) -> Option<PersistentList<T>> {
+        match &*self.head {
+            Cons(_, rest) => Some(PersistentList {
+                head: Rc::clone(rest),
+            }),
+            Nil => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(*self.head, Nil)
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.head.iter()
+    }
+
+    pub fn map<U, F>(&self, f: F) -> PersistentList<U>
+    where
+        F: FnMut(&T) -> U,
+    {
+        // Nothing can be shared with a list of a different element type.
+        let values: Vec<U> = self.iter().map(f).collect();
+        PersistentList::from_back(Rc::new(Nil), values)
+    }
+
+    /// Strong count of every node along the spine, starting at the head.
+    ///
+    /// A count above 1 means that node is shared with another version.
+    pub fn strong_counts(&self) -> Vec<usize> {
+        self.nodes().map(Rc::strong_count).collect()
+    }
+
+    /// Number of trailing nodes that `self` and `other` physically share.
+    pub fn shared_len(&self, other: &PersistentList<T>) -> usize {
+        let ours: Vec<_> = self.nodes().collect();
+        let theirs: Vec<_> = other.nodes().collect();
+        ours.iter()
+            .rev()
+            .zip(theirs.iter().rev())
+            .take_while(|(a, b)| Rc::ptr_eq(a, b))
+            .count()
+    }
+
+    pub fn ptr_eq(&self, other: &PersistentList<T>) -> bool {
+        Rc::ptr_eq(&self.head, &other.head)
+    }
+
+    // The `Rc` of every `Cons` node, skipping the trailing `Nil`.
+    fn nodes(&self) -> impl Iterator<Item = &Rc<List<T>>> {
+        let mut next = Some(&self.head);
+        std::iter::from_fn(move || {
+            let node = next?;
+            match &**node {
+                Cons(_, rest) => {
+                    next = Some(rest);
+                    Some(node)
+                }
+                Nil => None,
+            }
+        })
+    }
+
+    // Builds `values` in order in front of `tail`, allocating from the back.
+    fn from_back(tail: Rc<List<T>>, values: Vec<T>) -> PersistentList<T> {
+        let head = values
+            .into_iter()
+            .rev()
+            .fold(tail, |rest, value| Rc::new(Cons(value, rest)));
+        PersistentList { head }
+    }
+}
+
+impl<T: Clone> PersistentList<T> {
+    /// Copies the nodes of `self` in front of `other`, which is shared as a whole.
+    pub fn append(&self, other: &PersistentList<T>) -> PersistentList<T> {
+        if other.is_empty() {
+            return self.clone();
+        }
+        let values = self.iter().cloned().collect();
+        PersistentList::from_back(Rc::clone(&other.head), values)
+    }
+
+    /// Keeps the elements matching `predicate`.
+    ///
+    /// Everything after the last removed element is shared with `self`.
+    pub fn filter<P>(&self, mut predicate: P) -> PersistentList<T>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        let mut copied = Vec::new();
+        let mut pending = Vec::new();
+        let mut suffix = &self.head;
+        let mut cursor = &self.head;
+
+        while let Cons(value, rest) = &**cursor {
+            if predicate(value) {
+                pending.push(value);
+            } else {
+                copied.append(&mut pending);
+                suffix = rest;
+            }
+            cursor = rest;
+        }
+
+        let values = copied.into_iter().cloned().collect();
+        PersistentList::from_back(Rc::clone(suffix), values)
+    }
+
+    /// Copy-on-write access to the element at `index`.
+    ///
+    /// `Rc::make_mut` clones only the nodes up to `index` that are shared with another
+    /// version; the rest of the list stays shared.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index >= self.len() {
+            return None;
+        }
+
+        let mut node = Rc::make_mut(&mut self.head);
+        for _ in 0..index {
+            node = match node {
+                Cons(_, rest) => Rc::make_mut(rest),
+                Nil => return None,
+            };
+        }
+
+        match node {
+            Cons(value, _) => Some(value),
+            Nil => None,
+        }
+    }
+}
+
+impl<T> Clone for PersistentList<T> {
+    fn clone(&self) -> PersistentList<T> {
+        PersistentList {
+            head: Rc::clone(&self.head),
+        }
+    }
+}
+
+impl<T> Default for PersistentList<T> {
+    fn default() -> PersistentList<T> {
+        PersistentList::new()
+    }
+}
+
+impl<T> FromIterator<T> for PersistentList<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> PersistentList<T> {
+        PersistentList::from_back(Rc::new(Nil), iter.into_iter().collect())
+    }
+}
+
+impl<T: PartialEq> PartialEq for PersistentList<T> {
+    fn eq(&self, other: &PersistentList<T>) -> bool {
+        self.head == other.head
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PersistentList<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.head.fmt(f)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::rct::List::{Cons, Nil};
+    use crate::rct::List::{self, Cons, Nil};
+    use crate::rct::PersistentList;
+    use crate::test_util::LeakTracker;
     use std::rc::Rc;
 
     #[test]
     fn run() {
-        let a = Rc::new(Cons(5, Rc::new(Cons(10, Rc::new(Nil)))));
-        assert_eq!(1, Rc::strong_count(&a));
+        let tracker = LeakTracker::new();
+        {
+            let a = tracker.watch(
+                "a",
+                Rc::new(Cons(
+                    tracker.track(5),
+                    Rc::new(Cons(tracker.track(10), Rc::new(Nil))),
+                )),
+            );
+            assert_eq!(1, Rc::strong_count(&a));
+
+            let _b = Cons(tracker.track(3), Rc::clone(&a));
+            assert_eq!(2, Rc::strong_count(&a));
 
+            {
+                let _c = Cons(tracker.track(4), Rc::clone(&a));
+                assert_eq!(3, Rc::strong_count(&a));
+            }
+            // count after c gone out of scope.
+            assert_eq!(2, Rc::strong_count(&a));
+            assert_eq!(1, tracker.dropped());
+        }
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn eq_and_debug() {
+        let a = Rc::new(Cons(5, Rc::new(Cons(10, Rc::new(Nil)))));
         let b = Cons(3, Rc::clone(&a));
-        assert_eq!(2, Rc::strong_count(&a));
 
-        {
-            let c = Cons(4, Rc::clone(&a));
-            assert_eq!(3, Rc::strong_count(&a));
+        assert_eq!(format!("{b:?}"), "(3 5 10)");
+        let c = b.clone();
+        assert_eq!(c, b);
+        assert_ne!(*a, b);
+        // Cloning a node shares its tail instead of copying it.
+        assert_eq!(3, Rc::strong_count(&a));
+    }
+
+    #[test]
+    fn string_payloads() {
+        let shared = Rc::new(Cons(String::from("tail"), Rc::new(Nil)));
+        let a = Cons(String::from("a"), Rc::clone(&shared));
+        let b = Cons(String::from("b"), Rc::clone(&shared));
+
+        assert_eq!(format!("{a:?}"), r#"("a" "tail")"#);
+        assert_eq!(
+            b.iter().map(String::as_str).collect::<Vec<_>>(),
+            ["b", "tail"]
+        );
+        assert_eq!(3, Rc::strong_count(&shared));
+    }
+
+    #[test]
+    fn shared_payload_is_dropped_with_its_last_owner() {
+        let tracker = LeakTracker::new();
+        let shared = tracker.watch(
+            "shared",
+            Rc::new(Cons(
+                tracker.track(1),
+                Rc::new(Cons(tracker.track(2), Rc::new(Nil))),
+            )),
+        );
+        let a = Cons(tracker.track(3), Rc::clone(&shared));
+        let b = Cons(tracker.track(4), Rc::clone(&shared));
+        drop(shared);
+
+        drop(a);
+        assert_eq!(tracker.dropped(), 1);
+        drop(b);
+        assert_eq!(tracker.dropped(), 4);
+        tracker.assert_no_leaks();
+    }
+
+    const DEEP: i32 = 1_000_000;
+
+    fn deep_list() -> Rc<List<i32>> {
+        let mut list = Rc::new(Nil);
+        for i in 0..DEEP {
+            list = Rc::new(Cons(i, list));
         }
-        // count after c gone out of scope.
-        assert_eq!(2, Rc::strong_count(&a));
+        list
+    }
+
+    #[test]
+    fn deep_list_is_stack_safe() {
+        let a = deep_list();
+        let b = deep_list();
+        assert_eq!(a, b);
+        assert_eq!(a.iter().count(), DEEP as usize);
+        assert!(format!("{a:?}").starts_with("(999999 999998"));
+        drop(a);
+        drop(b);
+    }
+
+    #[test]
+    fn dropping_a_shared_tail_keeps_the_other_owner() {
+        let shared = deep_list();
+        let a = Cons(-1, Rc::clone(&shared));
+        let b = Cons(-2, Rc::clone(&shared));
+        drop(shared);
+
+        drop(a);
+        assert_eq!(b.iter().count(), DEEP as usize + 1);
+        drop(b);
+    }
+
+    #[test]
+    fn persistent_cons_head_and_tail() {
+        let empty = PersistentList::new();
+        let a = empty.cons(10).cons(5);
+        let b = a.cons(3);
+        let c = a.cons(4);
+
+        assert_eq!(a.head(), Some(&5));
+        assert_eq!(b.iter().copied().collect::<Vec<_>>(), vec![3, 5, 10]);
+        assert_eq!(c.iter().copied().collect::<Vec<_>>(), vec![4, 5, 10]);
+        assert!(b.tail().unwrap().ptr_eq(&a));
+        assert!(empty.head().is_none() && empty.tail().is_none());
+
+        // `a` is owned by the `a` handle itself plus the `b` and `c` nodes.
+        assert_eq!(a.strong_counts(), vec![3, 1]);
+        assert_eq!(b.shared_len(&c), 2);
+    }
+
+    #[test]
+    fn persistent_append_shares_the_right_hand_side() {
+        let left: PersistentList<i32> = (1..=2).collect();
+        let right: PersistentList<i32> = (3..=5).collect();
+        let both = left.append(&right);
+
+        assert_eq!(
+            both.iter().copied().collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(both.shared_len(&right), 3);
+        assert_eq!(both.shared_len(&left), 0);
+        assert_eq!(right.strong_counts(), vec![2, 1, 1]);
+
+        assert!(left.append(&PersistentList::new()).ptr_eq(&left));
+        assert!(PersistentList::new().append(&right).shared_len(&right) == 3);
+    }
+
+    #[test]
+    fn persistent_map_and_filter() {
+        let list: PersistentList<i32> = (1..=6).collect();
+
+        let doubled = list.map(|v| v * 2);
+        assert_eq!(
+            doubled.iter().copied().collect::<Vec<_>>(),
+            vec![2, 4, 6, 8, 10, 12]
+        );
+        assert_eq!(doubled.shared_len(&list), 0);
+
+        // Removing only `3` keeps `4 5 6` shared and copies `1 2`.
+        let without_three = list.filter(|v| *v != 3);
+        assert_eq!(
+            without_three.iter().copied().collect::<Vec<_>>(),
+            vec![1, 2, 4, 5, 6]
+        );
+        assert_eq!(without_three.shared_len(&list), 3);
+
+        let all = list.filter(|_| true);
+        assert!(all.ptr_eq(&list));
+
+        let labels = list.map(|v| format!("n{v}"));
+        assert_eq!(format!("{labels:?}"), r#"("n1" "n2" "n3" "n4" "n5" "n6")"#);
+    }
+
+    #[test]
+    fn persistent_get_mut_clones_only_the_shared_prefix() {
+        let base: PersistentList<i32> = (1..=5).collect();
+        let mut edited = base.clone();
+
+        *edited.get_mut(2).unwrap() = 30;
+
+        assert_eq!(
+            base.iter().copied().collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(
+            edited.iter().copied().collect::<Vec<_>>(),
+            vec![1, 2, 30, 4, 5]
+        );
+        // Nodes 0..=2 were copied, the `4 5` tail is still shared.
+        assert_eq!(edited.shared_len(&base), 2);
+        assert_eq!(edited.strong_counts(), vec![1, 1, 1, 2, 1]);
+
+        // Now the prefix is unique, so a second edit clones nothing.
+        *edited.get_mut(0).unwrap() = 10;
+        assert_eq!(edited.shared_len(&base), 2);
+        assert_eq!(edited.get_mut(5), None);
+    }
+
+    #[test]
+    fn to_dot_shows_shared_tails() {
+        let a = Rc::new(Cons(5, Rc::new(Cons(10, Rc::new(Nil)))));
+        let b = Rc::new(Cons(3, Rc::clone(&a)));
+        let c = Rc::new(Cons(4, Rc::clone(&a)));
+
+        assert_eq!(
+            super::to_dot(&[&b, &c]),
+            concat!(
+                "digraph {\n",
+                "    node [shape=box];\n",
+                "    n0 [label=\"3\\nstrong: 1, weak: 0\"];\n",
+                "    n1 [label=\"5\\nstrong: 3, weak: 0\"];\n",
+                "    n2 [label=\"10\\nstrong: 1, weak: 0\"];\n",
+                "    n3 [label=\"Nil\\nstrong: 1, weak: 0\"];\n",
+                "    n4 [label=\"4\\nstrong: 1, weak: 0\"];\n",
+                "    n0 -> n1;\n",
+                "    n1 -> n2;\n",
+                "    n2 -> n3;\n",
+                "    n4 -> n1;\n",
+                "}\n",
+            )
+        );
     }
 }
@@ -2,44 +2,355 @@ pub trait Messenger {
     fn send(&self, msg: &str);
 }
 
-pub struct LimitTracker<'a, T: Messenger> {
+impl<M: Messenger + ?Sized> Messenger for &M {
+    fn send(&self, msg: &str) {
+        (**self).send(msg);
+    }
+}
+
+/// Receives each notification as a `QuotaEvent` rather than a finished string.
+///
+/// Every `Messenger` is also an `EventMessenger` that sends `event.message`.
+pub trait EventMessenger {
+    fn notify(&self, event: &QuotaEvent);
+}
+
+impl<M: Messenger> EventMessenger for M {
+    fn notify(&self, event: &QuotaEvent) {
+        self.send(&event.message);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rising,
+    Falling,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaEvent {
+    pub severity: Severity,
+    pub value: usize,
+    pub max: usize,
+    /// The percentage of the threshold that was reached.
+    pub threshold: u32,
+    /// Whether the value went up or down to get here.
+    pub direction: Direction,
+    /// The threshold's template, rendered.
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Info,
+    Warning,
+    Urgent,
+    Error,
+}
+
+/// A level of usage, as a whole percentage of the quota, and what to send when it
+/// is reached.
+///
+/// `{value}`, `{max}` and `{percent}` in the template are replaced with the usage
+/// that reached it, and `{threshold}` with `percent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Threshold {
+    pub percent: u32,
+    pub severity: Severity,
+    pub template: String,
+}
+
+impl Threshold {
+    pub fn is_reached(&self, value: usize, max: usize) -> bool {
+        percent_of(value, max) >= u128::from(self.percent)
+    }
+
+    pub fn render(&self, value: usize, max: usize) -> String {
+        render(&self.template, self.percent, value, max)
+    }
+
+    fn event(&self, value: usize, max: usize) -> QuotaEvent {
+        QuotaEvent {
+            severity: self.severity,
+            value,
+            max,
+            threshold: self.percent,
+            direction: Direction::Rising,
+            message: self.render(value, max),
+        }
+    }
+
+    // Still counts as reached until usage drops more than `margin` points below it.
+    fn is_held(&self, value: usize, max: usize, margin: u32) -> bool {
+        percent_of(value, max) + u128::from(margin) >= u128::from(self.percent)
+    }
+}
+
+fn render(template: &str, threshold: u32, value: usize, max: usize) -> String {
+    template
+        .replace("{value}", &value.to_string())
+        .replace("{max}", &max.to_string())
+        .replace("{percent}", &percent_of(value, max).to_string())
+        .replace("{threshold}", &threshold.to_string())
+}
+
+/// The thresholds a `LimitTracker` reports, kept sorted by percentage.
+///
+/// Each threshold is reported once when usage reaches it. Usage then has to fall
+/// more than the hysteresis margin below it before the threshold can be reported
+/// again. Recovery notices are off unless a `recovery` template is set; a fall
+/// past several thresholds at once sends one, for the highest of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThresholdPolicy {
+    thresholds: Vec<Threshold>,
+    hysteresis: u32,
+    recovery: Option<String>,
+}
+
+impl ThresholdPolicy {
+    /// A policy with no thresholds, which never sends anything, and no margin.
+    /// Start from `default()` for the usual 75/90/100% warnings.
+    pub fn empty() -> ThresholdPolicy {
+        ThresholdPolicy {
+            thresholds: vec![],
+            hysteresis: 0,
+            recovery: None,
+        }
+    }
+
+    /// Sets the margin, in percentage points, for recovering from a threshold.
+    pub fn hysteresis(mut self, margin: u32) -> ThresholdPolicy {
+        self.hysteresis = margin;
+        self
+    }
+
+    /// Sends a recovery notice from `template` when usage leaves a reported band;
+    /// see `Threshold` for placeholders.
+    pub fn recovery(mut self, template: &str) -> ThresholdPolicy {
+        self.recovery = Some(template.to_string());
+        self
+    }
+
+    /// Adds a threshold, replacing any other one at the same percentage.
+    pub fn threshold(
+        mut self,
+        percent: u32,
+        severity: Severity,
+        template: &str,
+    ) -> ThresholdPolicy {
+        let threshold = Threshold {
+            percent,
+            severity,
+            template: template.to_string(),
+        };
+        match self
+            .thresholds
+            .binary_search_by_key(&percent, |t| t.percent)
+        {
+            Ok(i) => self.thresholds[i] = threshold,
+            Err(i) => self.thresholds.insert(i, threshold),
+        }
+        self
+    }
+
+    pub fn thresholds(&self) -> &[Threshold] {
+        &self.thresholds
+    }
+
+    /// The highest threshold that `value` reaches, if any.
+    pub fn reached(&self, value: usize, max: usize) -> Option<&Threshold> {
+        self.thresholds
+            .iter()
+            .rev()
+            .find(|threshold| threshold.is_reached(value, max))
+    }
+
+    /// Moves `band`, the index of the last threshold reported, to match `value`,
+    /// and returns the event to send if that crossed into a new band.
+    ///
+    /// Reaching a higher threshold reports it. Falling out of the current band
+    /// drops to the highest lower band still held, without reporting that one
+    /// again, and returns the recovery notice for the band left, if enabled. Lower
+    /// bands fallen past on the way get no notice of their own.
+    pub(crate) fn update(
+        &self,
+        band: &mut Option<usize>,
+        value: usize,
+        max: usize,
+    ) -> Option<QuotaEvent> {
+        // The highest threshold is the one reached, or the band left.
+        self.crossings(band, value, max)
+            .into_iter()
+            .max_by_key(|event| event.threshold)
+    }
+
+    /// Like `update`, but with an event for every threshold crossed between the
+    /// old band and the new one, in the order a steady climb or fall would have
+    /// crossed them.
+    pub(crate) fn crossings(
+        &self,
+        band: &mut Option<usize>,
+        value: usize,
+        max: usize,
+    ) -> Vec<QuotaEvent> {
+        let reached = self
+            .thresholds
+            .iter()
+            .rposition(|threshold| threshold.is_reached(value, max));
+        if let Some(top) = reached
+            && reached > *band
+        {
+            let from = band.map_or(0, |band| band + 1);
+            *band = reached;
+            return self.thresholds[from..=top]
+                .iter()
+                .map(|threshold| threshold.event(value, max))
+                .collect();
+        }
+
+        let Some(left) = *band else {
+            return vec![];
+        };
+        if self.thresholds[left].is_held(value, max, self.hysteresis) {
+            return vec![];
+        }
+        *band = self.thresholds[..left]
+            .iter()
+            .rposition(|threshold| threshold.is_held(value, max, self.hysteresis));
+        let Some(recovery) = &self.recovery else {
+            return vec![];
+        };
+        let to = band.map_or(0, |band| band + 1);
+        self.thresholds[to..=left]
+            .iter()
+            .rev()
+            .map(|threshold| QuotaEvent {
+                severity: Severity::Info,
+                value,
+                max,
+                threshold: threshold.percent,
+                direction: Direction::Falling,
+                message: render(recovery, threshold.percent, value, max),
+            })
+            .collect()
+    }
+}
+
+/// The original 75%, 90% and 100% warnings, with no margin and no recovery notices.
+///
+/// Both are opt-in: without a margin, usage hovering around a threshold reports
+/// it again on every crossing, and falling back under one sends nothing. Add them
+/// with `ThresholdPolicy::default().hysteresis(5).recovery("...")`.
+impl Default for ThresholdPolicy {
+    fn default() -> ThresholdPolicy {
+        ThresholdPolicy::empty()
+            .threshold(
+                75,
+                Severity::Warning,
+                "Warning: You've used up over 75% of your quota!",
+            )
+            .threshold(
+                90,
+                Severity::Urgent,
+                "Urgent warning: You've used up over 90% of your quota!",
+            )
+            .threshold(100, Severity::Error, "Error: You are over your quota!")
+    }
+}
+
+// Whole percent, rounded down. Done in `u128` so `value * 100` can't overflow, and
+// with no floats, so there is no NaN or infinity. An empty quota counts as 0% used
+// until anything is used, then as 100%.
+fn percent_of(value: usize, max: usize) -> u128 {
+    match (value, max) {
+        (0, _) => 0,
+        (_, 0) => 100,
+        _ => value as u128 * 100 / max as u128,
+    }
+}
+
+pub struct LimitTracker<'a, T: EventMessenger> {
     messenger: &'a T,
     value: usize,
     max: usize,
+    policy: ThresholdPolicy,
+    band: Option<usize>,
 }
 
 impl<'a, T> LimitTracker<'a, T>
 where
-    T: Messenger,
+    T: EventMessenger,
 {
+    /// Tracks usage with `ThresholdPolicy::default()`, so there is no hysteresis
+    /// margin and no recovery notice. Use `with_policy` to turn either on.
     pub fn new(messenger: &'a T, max: usize) -> LimitTracker<'a, T> {
+        LimitTracker::with_policy(messenger, max, ThresholdPolicy::default())
+    }
+
+    pub fn with_policy(
+        messenger: &'a T,
+        max: usize,
+        policy: ThresholdPolicy,
+    ) -> LimitTracker<'a, T> {
         LimitTracker {
             messenger,
             value: 0,
             max,
+            policy,
+            band: None,
         }
     }
 
+    /// Notifies the messenger only when `value` moves into a new band; see
+    /// `ThresholdPolicy`.
     pub fn set_value(&mut self, value: usize) {
+        if let Some(event) = self.update(value) {
+            self.messenger.notify(&event);
+        }
+    }
+
+    // `set_value` without the notifying, for callers that have to let go of the
+    // tracker before the messenger runs.
+    pub(crate) fn update(&mut self, value: usize) -> Option<QuotaEvent> {
         self.value = value;
+        self.policy.update(&mut self.band, self.value, self.max)
+    }
+
+    pub(crate) fn messenger(&self) -> &'a T {
+        self.messenger
+    }
 
-        let percentage_of_max = self.value as f64 / self.max as f64;
+    pub fn value(&self) -> usize {
+        self.value
+    }
 
-        if percentage_of_max >= 1.0 {
-            self.messenger.send("Error: You are over your quota!");
-        } else if percentage_of_max >= 0.9 {
-            self.messenger
-                .send("Urgent warning: You've used up over 90% of your quota!");
-        } else if percentage_of_max >= 0.75 {
-            self.messenger
-                .send("Warning: You've used up over 75% of your quota!");
-        }
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// The last threshold reported and not recovered from yet.
+    pub fn reported(&self) -> Option<&Threshold> {
+        self.band.map(|band| &self.policy.thresholds[band])
+    }
+
+    // Picks up where an earlier tracker left off, without notifying: `reported`
+    // becomes the band, or the highest lower threshold if the policy changed.
+    pub(crate) fn resume(mut self, value: usize, reported: Option<u32>) -> LimitTracker<'a, T> {
+        self.value = value;
+        self.band = reported.and_then(|percent| {
+            self.policy
+                .thresholds
+                .iter()
+                .rposition(|threshold| threshold.percent <= percent)
+        });
+        self
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::RecordingEventMessenger;
     use std::cell::RefCell;
 
     struct MockMessenger {
@@ -69,4 +380,217 @@ mod tests {
 
         assert_eq!(mock_messenger.sent_messages.borrow().len(), 1);
     }
+
+    #[test]
+    fn default_policy_keeps_the_original_messages() {
+        let mock_messenger = MockMessenger::new();
+        let mut limit_tracker = LimitTracker::new(&mock_messenger, 100);
+
+        for value in [10, 74, 75, 89, 90, 99, 100, 150] {
+            limit_tracker.set_value(value);
+        }
+
+        assert_eq!(
+            *mock_messenger.sent_messages.borrow(),
+            vec![
+                "Warning: You've used up over 75% of your quota!",
+                "Urgent warning: You've used up over 90% of your quota!",
+                "Error: You are over your quota!",
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_policy_renders_templates() {
+        let policy = ThresholdPolicy::empty()
+            .threshold(50, Severity::Info, "half: {value}/{max} ({percent}%)")
+            .threshold(200, Severity::Error, "double: {value}/{max} ({percent}%)")
+            .threshold(50, Severity::Info, "{value}/{max} ({percent}%)");
+        assert_eq!(
+            policy
+                .thresholds()
+                .iter()
+                .map(|t| t.percent)
+                .collect::<Vec<_>>(),
+            vec![50, 200]
+        );
+
+        let mock_messenger = MockMessenger::new();
+        let mut limit_tracker = LimitTracker::with_policy(&mock_messenger, 30, policy);
+        for value in [14, 15, 59, 60, 61] {
+            limit_tracker.set_value(value);
+        }
+
+        assert_eq!(
+            *mock_messenger.sent_messages.borrow(),
+            vec!["15/30 (50%)", "double: 60/30 (200%)"]
+        );
+    }
+
+    #[test]
+    fn empty_quota() {
+        let mock_messenger = MockMessenger::new();
+        let policy = ThresholdPolicy::default().threshold(100, Severity::Error, "{percent}%");
+        let mut limit_tracker = LimitTracker::with_policy(&mock_messenger, 0, policy);
+
+        limit_tracker.set_value(0);
+        assert!(mock_messenger.sent_messages.borrow().is_empty());
+
+        limit_tracker.set_value(1);
+        assert_eq!(*mock_messenger.sent_messages.borrow(), vec!["100%"]);
+    }
+
+    #[test]
+    fn huge_values_do_not_overflow() {
+        let policy = ThresholdPolicy::default();
+        let half = usize::MAX / 2;
+
+        assert!(policy.reached(usize::MAX / 100 + 1, usize::MAX).is_none());
+        let threshold = policy.reached(usize::MAX, usize::MAX).unwrap();
+        assert_eq!(threshold.severity, Severity::Error);
+
+        let threshold = policy.reached(half / 100 * 80, half).unwrap();
+        assert_eq!(threshold.severity, Severity::Warning);
+
+        let over = Threshold {
+            percent: u32::MAX,
+            severity: Severity::Error,
+            template: String::from("{percent}%"),
+        };
+        assert!(over.is_reached(usize::MAX, 1));
+        assert_eq!(
+            over.render(usize::MAX, 1),
+            format!("{}%", usize::MAX as u128 * 100)
+        );
+    }
+
+    #[test]
+    fn events_carry_the_threshold_and_direction() {
+        let recorder = RecordingEventMessenger::new();
+        let policy = ThresholdPolicy::default().recovery("recovered");
+        let mut limit_tracker = LimitTracker::with_policy(&recorder, 200, policy);
+
+        limit_tracker.set_value(190);
+        limit_tracker.set_value(210);
+        limit_tracker.set_value(150);
+        limit_tracker.set_value(10);
+        limit_tracker.set_value(10);
+
+        let events = recorder.take_events();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| (e.severity, e.value, e.threshold, e.direction))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Severity::Urgent, 190, 90, Direction::Rising),
+                (Severity::Error, 210, 100, Direction::Rising),
+                (Severity::Info, 150, 100, Direction::Falling),
+                (Severity::Info, 10, 75, Direction::Falling),
+            ]
+        );
+        assert!(events.iter().all(|e| e.max == 200));
+        assert_eq!(events[1].message, "Error: You are over your quota!");
+    }
+
+    #[test]
+    fn oscillating_values_notify_once_per_band() {
+        let mock_messenger = MockMessenger::new();
+        let policy = ThresholdPolicy::default()
+            .hysteresis(5)
+            .recovery("back under {threshold}% at {value}");
+        let mut limit_tracker = LimitTracker::with_policy(&mock_messenger, 100, policy);
+
+        // Hovering around 75% stays inside the margin, so only the first crossing
+        // is sent.
+        for value in [74, 76, 74, 75, 71, 80, 70, 76] {
+            limit_tracker.set_value(value);
+        }
+        assert_eq!(
+            *mock_messenger.sent_messages.borrow(),
+            vec!["Warning: You've used up over 75% of your quota!"]
+        );
+
+        mock_messenger.sent_messages.borrow_mut().clear();
+        // Falling out of 100% lands back in the 90% band, which isn't sent again.
+        for value in [69, 72, 75, 101, 96, 99, 100, 94, 85, 91, 60] {
+            limit_tracker.set_value(value);
+        }
+        assert_eq!(
+            *mock_messenger.sent_messages.borrow(),
+            vec![
+                "back under 75% at 69",
+                "Warning: You've used up over 75% of your quota!",
+                "Error: You are over your quota!",
+                "back under 100% at 94",
+                // Falling past 75% as well sends no notice of its own.
+                "back under 90% at 60",
+            ]
+        );
+    }
+
+    #[test]
+    fn default_policy_repeats_warnings_when_oscillating() {
+        let mock_messenger = MockMessenger::new();
+        let mut limit_tracker = LimitTracker::new(&mock_messenger, 100);
+
+        // No margin: every time usage gets back to 75% the warning goes out again,
+        // and dropping under it is silent.
+        for value in [74, 75, 74, 76, 76, 70, 80, 50] {
+            limit_tracker.set_value(value);
+        }
+        assert_eq!(
+            *mock_messenger.sent_messages.borrow(),
+            vec![
+                "Warning: You've used up over 75% of your quota!",
+                "Warning: You've used up over 75% of your quota!",
+                "Warning: You've used up over 75% of your quota!",
+            ]
+        );
+        assert!(limit_tracker.reported().is_none());
+    }
+
+    #[test]
+    fn recovery_notices_are_opt_in() {
+        let mock_messenger = MockMessenger::new();
+        let mut limit_tracker = LimitTracker::new(&mock_messenger, 100);
+
+        for value in [80, 80, 74, 10, 76] {
+            limit_tracker.set_value(value);
+        }
+        assert_eq!(
+            *mock_messenger.sent_messages.borrow(),
+            vec![
+                "Warning: You've used up over 75% of your quota!",
+                "Warning: You've used up over 75% of your quota!",
+            ]
+        );
+        assert_eq!(limit_tracker.reported().unwrap().percent, 75);
+    }
+
+    #[test]
+    fn zero_margin_recovers_right_below_the_threshold() {
+        let policy = ThresholdPolicy::empty()
+            .threshold(50, Severity::Warning, "up")
+            .recovery("down");
+        let mut band = None;
+        let messages: Vec<_> = [50, 49, 49, 50, 50, 0]
+            .into_iter()
+            .map(|value| policy.update(&mut band, value, 100).map(|e| e.message))
+            .collect();
+
+        assert_eq!(
+            messages,
+            vec![
+                Some(String::from("up")),
+                Some(String::from("down")),
+                None,
+                Some(String::from("up")),
+                None,
+                Some(String::from("down")),
+            ]
+        );
+        assert_eq!(band, None);
+    }
 }
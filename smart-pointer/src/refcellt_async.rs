@@ -0,0 +1,293 @@
+//! An async `LimitTracker` for services that can't block on `Messenger::send`.
+//!
+//! Built on `trpl`, like the async chapters. A tracker with a timeout races the
+//! delivery against `trpl::sleep`, which needs the tokio runtime that `trpl::run`
+//! starts; without a timeout any executor will do.
+
+use std::fmt;
+use std::future::Future;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use trpl::{Either, Receiver, Sender};
+
+use crate::refcellt::{Messenger, ThresholdPolicy};
+
+pub trait AsyncMessenger {
+    /// Completes once `msg` has been delivered.
+    fn send(&self, msg: &str) -> impl Future<Output = ()>;
+}
+
+/// A message that wasn't delivered within the tracker's timeout.
+///
+/// The send is abandoned, not undone: a slow messenger may still deliver it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedOut {
+    pub message: String,
+    pub after: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "message not delivered within {:?}: {}",
+            self.after, self.message
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+pub struct AsyncLimitTracker<'a, T: AsyncMessenger> {
+    messenger: &'a T,
+    value: usize,
+    max: usize,
+    policy: ThresholdPolicy,
+    band: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl<'a, T> AsyncLimitTracker<'a, T>
+where
+    T: AsyncMessenger,
+{
+    pub fn new(messenger: &'a T, max: usize) -> AsyncLimitTracker<'a, T> {
+        AsyncLimitTracker::with_policy(messenger, max, ThresholdPolicy::default())
+    }
+
+    pub fn with_policy(
+        messenger: &'a T,
+        max: usize,
+        policy: ThresholdPolicy,
+    ) -> AsyncLimitTracker<'a, T> {
+        AsyncLimitTracker {
+            messenger,
+            value: 0,
+            max,
+            policy,
+            band: None,
+            timeout: None,
+        }
+    }
+
+    /// Gives up on any message not delivered within `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> AsyncLimitTracker<'a, T> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Like `LimitTracker::set_value`, and waits for the message, if any, to be
+    /// delivered. The value and band are updated even if delivery times out.
+    pub async fn set_value(&mut self, value: usize) -> Result<(), TimedOut> {
+        self.value = value;
+
+        let Some(event) = self.policy.update(&mut self.band, self.value, self.max) else {
+            return Ok(());
+        };
+        let delivery = self.messenger.send(&event.message);
+        match self.timeout {
+            None => {
+                delivery.await;
+                Ok(())
+            }
+            Some(after) => match trpl::race(delivery, trpl::sleep(after)).await {
+                Either::Left(()) => Ok(()),
+                Either::Right(()) => Err(TimedOut {
+                    message: event.message,
+                    after,
+                }),
+            },
+        }
+    }
+
+    pub fn value(&self) -> usize {
+        self.value
+    }
+}
+
+/// Runs a synchronous `Messenger` on a thread of its own, so sending only has to
+/// wait for that thread and doesn't block the task.
+///
+/// Messages are passed over a `trpl::channel` and delivered in order. Dropping
+/// the bridge delivers what is still queued, then stops the thread. If the
+/// messenger panics, the thread stops there: that message and the ones queued
+/// after it are dropped, and whoever waits on them is woken up rather than left
+/// waiting.
+pub struct MessengerBridge {
+    tx: Option<Sender<(String, Sender<()>)>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl MessengerBridge {
+    pub fn new<M: Messenger + Send + 'static>(messenger: M) -> MessengerBridge {
+        let (tx, mut rx) = trpl::channel::<(String, Sender<()>)>();
+        let worker = thread::spawn(move || {
+            // Each `done` is dropped once its message is handled, or while
+            // unwinding if `send` panics; either way its waiter wakes.
+            while let Some((msg, done)) = rx.blocking_recv() {
+                messenger.send(&msg);
+                let _ = done.send(());
+            }
+        });
+        MessengerBridge {
+            tx: Some(tx),
+            worker: Some(worker),
+        }
+    }
+}
+
+impl AsyncMessenger for MessengerBridge {
+    fn send(&self, msg: &str) -> impl Future<Output = ()> {
+        let (done, mut delivered): (Sender<()>, Receiver<()>) = trpl::channel();
+        if let Some(tx) = &self.tx {
+            // If the worker is gone, `done` is dropped here and the wait ends at
+            // once.
+            let _ = tx.send((msg.to_string(), done));
+        }
+        async move {
+            delivered.recv().await;
+        }
+    }
+}
+
+impl Drop for MessengerBridge {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsyncLimitTracker, AsyncMessenger, MessengerBridge, TimedOut};
+    use crate::refcellt::Messenger;
+    use crate::refcellt_messengers::ChannelMessenger;
+    use crate::test_util::FailingMessenger;
+    use std::cell::RefCell;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // Delivers after `delay`, without blocking the task while it waits.
+    #[derive(Default)]
+    struct SlowMessenger {
+        delay: Duration,
+        sent_messages: RefCell<Vec<String>>,
+    }
+
+    impl AsyncMessenger for SlowMessenger {
+        async fn send(&self, msg: &str) {
+            trpl::sleep(self.delay).await;
+            self.sent_messages.borrow_mut().push(msg.to_string());
+        }
+    }
+
+    #[test]
+    fn set_value_waits_for_delivery() {
+        let messenger = SlowMessenger {
+            delay: Duration::from_millis(20),
+            ..SlowMessenger::default()
+        };
+        let mut tracker = AsyncLimitTracker::new(&messenger, 100);
+
+        trpl::run(async {
+            tracker.set_value(10).await.unwrap();
+            tracker.set_value(80).await.unwrap();
+            assert_eq!(messenger.sent_messages.borrow().len(), 1);
+            tracker.set_value(100).await.unwrap();
+        });
+        assert_eq!(
+            *messenger.sent_messages.borrow(),
+            vec![
+                "Warning: You've used up over 75% of your quota!",
+                "Error: You are over your quota!",
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_delivery_times_out() {
+        let messenger = SlowMessenger {
+            delay: Duration::from_secs(5),
+            ..SlowMessenger::default()
+        };
+        let mut tracker =
+            AsyncLimitTracker::new(&messenger, 100).timeout(Duration::from_millis(20));
+
+        let result = trpl::run(tracker.set_value(100));
+        assert_eq!(
+            result,
+            Err(TimedOut {
+                message: String::from("Error: You are over your quota!"),
+                after: Duration::from_millis(20),
+            })
+        );
+        assert!(messenger.sent_messages.borrow().is_empty());
+        assert_eq!(tracker.value(), 100);
+
+        // The threshold counts as reported, so it isn't sent again.
+        assert_eq!(trpl::run(tracker.set_value(100)), Ok(()));
+    }
+
+    #[test]
+    fn bridge_delivers_through_a_sync_messenger() {
+        let (tx, rx) = mpsc::channel();
+        let bridge = MessengerBridge::new(ChannelMessenger::new(tx));
+        let mut tracker = AsyncLimitTracker::new(&bridge, 10).timeout(Duration::from_secs(5));
+
+        trpl::run(async {
+            for value in 1..=10 {
+                tracker.set_value(value).await.unwrap();
+            }
+        });
+        drop(tracker);
+        drop(bridge);
+
+        let received: Vec<String> = rx.iter().collect();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[2], "Error: You are over your quota!");
+    }
+
+    #[test]
+    fn bridge_times_out_but_still_delivers() {
+        struct Blocking(Arc<Mutex<Vec<String>>>);
+
+        impl Messenger for Blocking {
+            fn send(&self, msg: &str) {
+                thread::sleep(Duration::from_millis(100));
+                self.0.lock().unwrap().push(msg.to_string());
+            }
+        }
+
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let bridge = MessengerBridge::new(Blocking(Arc::clone(&delivered)));
+        let mut tracker = AsyncLimitTracker::new(&bridge, 10).timeout(Duration::from_millis(10));
+
+        assert!(trpl::run(tracker.set_value(10)).is_err());
+        assert!(delivered.lock().unwrap().is_empty());
+
+        // Dropping the bridge waits for the queue to drain.
+        drop(tracker);
+        drop(bridge);
+        assert_eq!(
+            *delivered.lock().unwrap(),
+            vec!["Error: You are over your quota!"]
+        );
+    }
+
+    #[test]
+    fn bridge_wakes_waiters_when_the_messenger_panics() {
+        let bridge = MessengerBridge::new(FailingMessenger::new());
+        let mut tracker = AsyncLimitTracker::new(&bridge, 10).timeout(Duration::from_secs(5));
+
+        // Neither the failed message nor one sent after it is left waiting.
+        trpl::run(async {
+            assert_eq!(tracker.set_value(8).await, Ok(()));
+            assert_eq!(tracker.set_value(10).await, Ok(()));
+        });
+    }
+}
@@ -0,0 +1,517 @@
+//! `Messenger` implementations for real use.
+//!
+//! `send` can't report failure, so the I/O backends keep the first error for
+//! `take_error`, and the channel backend remembers when its receiver is gone.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Stderr, Stdout, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::refcellt::Messenger;
+use crate::refcellt_window::{Clock, SystemClock};
+
+/// Writes each message on its own line and flushes.
+pub struct WriterMessenger<W: Write> {
+    writer: Mutex<W>,
+    error: Mutex<Option<io::Error>>,
+}
+
+impl<W: Write> WriterMessenger<W> {
+    pub fn new(writer: W) -> WriterMessenger<W> {
+        WriterMessenger {
+            writer: Mutex::new(writer),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// The first write error since the last call, if any.
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.error.lock().unwrap().take()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+
+    fn write_line(&self, line: &str) {
+        let result = {
+            let mut writer = self.writer.lock().unwrap();
+            writeln!(writer, "{line}").and_then(|()| writer.flush())
+        };
+        if let Err(err) = result {
+            self.error.lock().unwrap().get_or_insert(err);
+        }
+    }
+}
+
+impl WriterMessenger<Stdout> {
+    pub fn stdout() -> WriterMessenger<Stdout> {
+        WriterMessenger::new(io::stdout())
+    }
+}
+
+impl WriterMessenger<Stderr> {
+    pub fn stderr() -> WriterMessenger<Stderr> {
+        WriterMessenger::new(io::stderr())
+    }
+}
+
+impl<W: Write> Messenger for WriterMessenger<W> {
+    fn send(&self, msg: &str) {
+        self.write_line(msg);
+    }
+}
+
+/// Appends each message to a file, one line per line of the message, each starting
+/// with a UTC timestamp such as `2024-05-01T12:00:00.000Z`.
+pub struct LogFileMessenger {
+    file: WriterMessenger<File>,
+}
+
+impl LogFileMessenger {
+    /// Opens `path` for appending, creating it if needed. Existing lines are kept.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<LogFileMessenger> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFileMessenger {
+            file: WriterMessenger::new(file),
+        })
+    }
+
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.file.take_error()
+    }
+}
+
+impl Messenger for LogFileMessenger {
+    fn send(&self, msg: &str) {
+        let timestamp = timestamp(SystemTime::now());
+        let lines: Vec<_> = msg
+            .split('\n')
+            .map(|line| format!("{timestamp} {line}"))
+            .collect();
+        // One write for the whole message, so it isn't interleaved with others.
+        self.file.write_line(&lines.join("\n"));
+    }
+}
+
+// RFC 3339 in UTC with milliseconds. Times before 1970 are written as 1970.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Days since 1970-01-01 to a (year, month, day) in the proleptic Gregorian
+// calendar, counting in 400-year eras that start on March 1st.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Forwards each message to an `mpsc` channel.
+///
+/// Once the receiver is dropped, messages are discarded and `is_disconnected`
+/// returns `true`.
+pub struct ChannelMessenger {
+    tx: Sender<String>,
+    disconnected: AtomicBool,
+}
+
+impl ChannelMessenger {
+    pub fn new(tx: Sender<String>) -> ChannelMessenger {
+        ChannelMessenger {
+            tx,
+            disconnected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+}
+
+impl Messenger for ChannelMessenger {
+    fn send(&self, msg: &str) {
+        if self.tx.send(msg.to_string()).is_err() {
+            self.disconnected.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Collects messages and passes them on to `inner` as one, joined by newlines.
+///
+/// A batch goes out once it has `max_messages` messages, or on the first `send`
+/// or `tick` after its oldest message is `max_age` old. Whatever is left is sent
+/// on `flush` and on drop.
+pub struct BatchingMessenger<M: Messenger, C: Clock = SystemClock> {
+    inner: M,
+    max_messages: usize,
+    max_age: Duration,
+    clock: C,
+    batch: Mutex<Batch>,
+    // Held from taking a batch until it is delivered, so batches reach `inner` in
+    // the order they were taken. Sends that only add to the batch don't wait on it.
+    delivery: Mutex<()>,
+}
+
+#[derive(Default)]
+struct Batch {
+    messages: Vec<String>,
+    started: Option<Instant>,
+}
+
+impl<M: Messenger> BatchingMessenger<M> {
+    pub fn new(inner: M, max_messages: usize, max_age: Duration) -> BatchingMessenger<M> {
+        BatchingMessenger::with_clock(inner, max_messages, max_age, SystemClock)
+    }
+}
+
+impl<M: Messenger, C: Clock> BatchingMessenger<M, C> {
+    /// A `max_messages` of 0 is treated as 1.
+    pub fn with_clock(
+        inner: M,
+        max_messages: usize,
+        max_age: Duration,
+        clock: C,
+    ) -> BatchingMessenger<M, C> {
+        BatchingMessenger {
+            inner,
+            max_messages: max_messages.max(1),
+            max_age,
+            clock,
+            batch: Mutex::new(Batch::default()),
+            delivery: Mutex::new(()),
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Sends the batch if its oldest message is `max_age` old. Call this from a
+    /// timer to keep quiet periods from holding messages back.
+    pub fn tick(&self) {
+        let now = self.clock.now();
+        self.deliver_if(|batch| self.is_due(batch, now));
+    }
+
+    pub fn flush(&self) {
+        self.deliver_if(|_| true);
+    }
+
+    fn is_due(&self, batch: &Batch, now: Instant) -> bool {
+        batch.messages.len() >= self.max_messages
+            || batch
+                .started
+                .is_some_and(|started| now.duration_since(started) >= self.max_age)
+    }
+
+    // Takes the batch if `ready` says so and sends it. Only the delivery lock is
+    // held while `inner` runs, so senders can keep adding to the next batch.
+    fn deliver_if(&self, ready: impl FnOnce(&Batch) -> bool) {
+        let _delivery = lock(&self.delivery);
+        let batch = {
+            let mut batch = lock(&self.batch);
+            if !ready(&batch) {
+                return;
+            }
+            std::mem::take(&mut *batch)
+        };
+        if !batch.messages.is_empty() {
+            self.inner.send(&batch.messages.join("\n"));
+        }
+    }
+}
+
+// A panic in `inner` can't leave a batch half-changed, and `Drop` flushes through
+// these locks, so a poisoned lock is used as it is.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<M: Messenger, C: Clock> Messenger for BatchingMessenger<M, C> {
+    fn send(&self, msg: &str) {
+        let now = self.clock.now();
+        let due = {
+            let mut batch = lock(&self.batch);
+            batch.started.get_or_insert(now);
+            batch.messages.push(msg.to_string());
+            self.is_due(&batch, now)
+        };
+        // Checked again once it's our turn to deliver, since another thread may
+        // have sent this batch meanwhile.
+        if due {
+            self.deliver_if(|batch| self.is_due(batch, now));
+        }
+    }
+}
+
+impl<M: Messenger, C: Clock> Drop for BatchingMessenger<M, C> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BatchingMessenger, ChannelMessenger, LogFileMessenger, WriterMessenger, timestamp,
+    };
+    use crate::refcellt::{LimitTracker, Messenger};
+    use crate::refcellt_sync::SharedLimitTracker;
+    use crate::test_util::{
+        FailingMessenger, FakeClock, Recorded, RecordingMessenger, SyncRecordingMessenger, TempFile,
+    };
+    use std::fs;
+    use std::io::{self, Write};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn writer_writes_one_line_per_message() {
+        let messenger = WriterMessenger::new(Vec::new());
+        let mut tracker = LimitTracker::new(&messenger, 100);
+        tracker.set_value(80);
+        tracker.set_value(100);
+        drop(tracker);
+
+        assert!(messenger.take_error().is_none());
+        assert_eq!(
+            String::from_utf8(messenger.into_inner()).unwrap(),
+            "Warning: You've used up over 75% of your quota!\n\
+             Error: You are over your quota!\n"
+        );
+    }
+
+    #[test]
+    fn writer_keeps_the_first_error() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk on fire"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let messenger = WriterMessenger::new(Broken);
+        messenger.send("a");
+        messenger.send("b");
+        assert_eq!(messenger.take_error().unwrap().to_string(), "disk on fire");
+        assert!(messenger.take_error().is_none());
+    }
+
+    #[test]
+    fn log_file_appends_timestamped_lines() {
+        let file = TempFile::new("append.log");
+        {
+            let messenger = LogFileMessenger::open(file.path()).unwrap();
+            messenger.send("first");
+        }
+        let messenger = LogFileMessenger::open(file.path()).unwrap();
+        messenger.send("second\nthird");
+        assert!(messenger.take_error().is_none());
+
+        let contents = fs::read_to_string(file.path()).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for (line, message) in lines.iter().zip(["first", "second", "third"]) {
+            let (stamp, rest) = line.split_once(' ').unwrap();
+            assert_eq!(rest, message);
+            assert_eq!(stamp.len(), "2024-05-01T12:00:00.000Z".len());
+            assert!(stamp.starts_with("20") && stamp.ends_with('Z'), "{stamp}");
+        }
+        // Both lines of one message share a timestamp.
+        assert_eq!(lines[1][..24], lines[2][..24]);
+    }
+
+    #[test]
+    fn log_file_open_fails_for_a_directory() {
+        assert!(LogFileMessenger::open(std::env::temp_dir()).is_err());
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        let at = |secs: u64, millis: u64| UNIX_EPOCH + Duration::from_millis(secs * 1000 + millis);
+        assert_eq!(timestamp(at(0, 0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(at(1_700_000_000, 123)),
+            "2023-11-14T22:13:20.123Z"
+        );
+        assert_eq!(timestamp(at(951_782_400, 0)), "2000-02-29T00:00:00.000Z");
+        assert_eq!(
+            timestamp(at(4_107_542_399, 999)),
+            "2100-02-28T23:59:59.999Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH - Duration::from_secs(1)),
+            "1970-01-01T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn channel_forwards_from_another_thread() {
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let tracker = SharedLimitTracker::new(ChannelMessenger::new(tx), 10);
+            for _ in 0..10 {
+                tracker.record(1);
+            }
+        });
+
+        let received: Vec<String> = rx.iter().collect();
+        assert_eq!(
+            received,
+            vec![
+                "Warning: You've used up over 75% of your quota!",
+                "Urgent warning: You've used up over 90% of your quota!",
+                "Error: You are over your quota!",
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_notices_a_dropped_receiver() {
+        let (tx, rx) = mpsc::channel();
+        let messenger = ChannelMessenger::new(tx);
+        messenger.send("kept");
+        assert_eq!(rx.recv().unwrap(), "kept");
+
+        drop(rx);
+        assert!(!messenger.is_disconnected());
+        messenger.send("lost");
+        assert!(messenger.is_disconnected());
+    }
+
+    #[test]
+    fn batching_by_size() {
+        let inner = RecordingMessenger::new();
+        let batching = BatchingMessenger::new(&inner, 3, Duration::from_secs(3600));
+        batching.send("a");
+        batching.send("b");
+        inner.assert_nothing_sent();
+        batching.send("c");
+        batching.send("d");
+        inner.assert_sent(&["a\nb\nc"]);
+
+        batching.flush();
+        batching.flush();
+        inner.assert_sent(&["d"]);
+
+        batching.send("e");
+        drop(batching);
+        inner.assert_sent(&["e"]);
+    }
+
+    #[test]
+    fn batching_by_age() {
+        let inner = RecordingMessenger::new();
+        let clock = FakeClock::new();
+        let batching = BatchingMessenger::with_clock(&inner, 100, Duration::from_secs(10), &clock);
+
+        batching.send("a");
+        clock.advance(Duration::from_secs(9));
+        batching.tick();
+        inner.assert_nothing_sent();
+        clock.advance(Duration::from_secs(1));
+        batching.tick();
+        inner.assert_sent(&["a"]);
+
+        // The age counts from the oldest message in the batch.
+        batching.send("b");
+        clock.advance(Duration::from_secs(6));
+        batching.send("c");
+        clock.advance(Duration::from_secs(4));
+        batching.send("d");
+        inner.assert_sent(&["b\nc\nd"]);
+        batching.tick();
+        inner.assert_nothing_sent();
+    }
+
+    #[test]
+    fn batches_arrive_in_the_order_they_were_taken() {
+        // Every other delivery is slow, so a later batch would overtake it if
+        // nothing kept them in order.
+        struct Uneven(SyncRecordingMessenger, AtomicUsize);
+        impl Messenger for Uneven {
+            fn send(&self, msg: &str) {
+                if self.1.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) {
+                    thread::sleep(Duration::from_micros(200));
+                }
+                self.0.send(msg);
+            }
+        }
+
+        let inner = Uneven(SyncRecordingMessenger::new(), AtomicUsize::new(0));
+        let batching = BatchingMessenger::new(&inner, 1000, Duration::from_secs(3600));
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    while !done.load(Ordering::SeqCst) {
+                        batching.flush();
+                    }
+                });
+            }
+            for i in 0..500 {
+                batching.send(&i.to_string());
+                if i % 10 == 0 {
+                    thread::sleep(Duration::from_micros(50));
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        batching.flush();
+
+        let received: Vec<i32> = inner
+            .0
+            .take()
+            .iter()
+            .flat_map(|batch| batch.lines())
+            .map(|line| line.parse().unwrap())
+            .collect();
+        assert_eq!(received, (0..500).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dropping_after_a_failed_delivery_does_not_panic() {
+        let inner = FailingMessenger::new();
+        let batching = BatchingMessenger::new(&inner, 1, Duration::from_secs(3600));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| batching.send("lost")));
+        assert!(result.is_err());
+
+        batching.flush();
+        drop(batching);
+        inner.delivered().assert_nothing_sent();
+    }
+}
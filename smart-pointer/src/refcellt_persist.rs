@@ -0,0 +1,426 @@
+//! Saving a `LimitTracker` so a restarted process carries on where it stopped.
+//!
+//! The state is a few lines of text:
+//!
+//! ```text
+//! limit-tracker v1
+//! value 80
+//! max 100
+//! reported 75
+//! checksum 85a2ba4e12067703
+//! ```
+//!
+//! `reported` is the percentage of the last threshold reported, or `none`. The
+//! checksum covers every line before it, so a file cut short or changed on disk
+//! is caught when loading instead of silently restoring the wrong usage.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::refcellt::{EventMessenger, LimitTracker, ThresholdPolicy};
+
+pub const VERSION: u32 = 1;
+
+const MAGIC: &str = "limit-tracker v";
+
+// Numbers the temporary files, so saves racing within one process don't share one.
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackerState {
+    pub value: usize,
+    pub max: usize,
+    /// The percentage of the last threshold reported and not recovered from.
+    pub reported: Option<u32>,
+}
+
+impl TrackerState {
+    pub fn encode(&self) -> String {
+        let reported = match self.reported {
+            Some(percent) => percent.to_string(),
+            None => String::from("none"),
+        };
+        let body = format!(
+            "{MAGIC}{VERSION}\nvalue {}\nmax {}\nreported {reported}\n",
+            self.value, self.max
+        );
+        let checksum = checksum(&body);
+        format!("{body}checksum {checksum:016x}\n")
+    }
+
+    pub fn decode(text: &str) -> Result<TrackerState, LoadError> {
+        // Without a full first line there's no telling what the file was meant to be.
+        let Some((header, _)) = text.split_once('\n') else {
+            return Err(LoadError::Truncated);
+        };
+        let version = header.strip_prefix(MAGIC).ok_or(LoadError::UnknownFormat)?;
+        let version = version.parse().map_err(|_| LoadError::UnknownFormat)?;
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+
+        // Everything is written in one go, so a file that doesn't end with the
+        // checksum line was cut short.
+        let body_len = text.trim_end_matches('\n').rfind('\n').map_or(0, |i| i + 1);
+        let (body, last) = text.split_at(body_len);
+        let Some(expected) = last
+            .strip_prefix("checksum ")
+            .and_then(|rest| rest.strip_suffix('\n'))
+        else {
+            return Err(LoadError::Truncated);
+        };
+        if u64::from_str_radix(expected, 16) != Ok(checksum(body)) {
+            return Err(LoadError::ChecksumMismatch);
+        }
+
+        let lines: Vec<&str> = body.lines().collect();
+        let field = |line: usize, name: &str| {
+            lines
+                .get(line - 1)
+                .and_then(|text| text.strip_prefix(name))
+                .and_then(|rest| rest.strip_prefix(' '))
+                .ok_or(LoadError::Corrupt { line })
+        };
+        fn parse<F: FromStr>(line: usize, text: &str) -> Result<F, LoadError> {
+            text.parse().map_err(|_| LoadError::Corrupt { line })
+        }
+        let value = parse(2, field(2, "value")?)?;
+        let max = parse(3, field(3, "max")?)?;
+        let reported = match field(4, "reported")? {
+            "none" => None,
+            percent => Some(parse(4, percent)?),
+        };
+        if lines.len() > 4 {
+            return Err(LoadError::Corrupt { line: 5 });
+        }
+        Ok(TrackerState {
+            value,
+            max,
+            reported,
+        })
+    }
+
+    /// Writes to a temporary file next to `path` and renames it over `path`, so a
+    /// crash leaves either the old state or the new one, never half of each.
+    ///
+    /// The temporary file is named after the process and a counter, so
+    /// concurrent saves to the same path don't overwrite each other's; the last
+    /// rename wins. On Unix the directory is synced too, so the rename itself
+    /// survives a crash. Elsewhere a crash right after saving can still bring
+    /// back the old state.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp = path.as_os_str().to_owned();
+        let n = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
+        temp.push(format!(".{}.{n}.tmp", process::id()));
+
+        let result = (|| {
+            let mut file = File::create(&temp)?;
+            file.write_all(self.encode().as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temp, path)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result?;
+        sync_parent(path)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<TrackerState, LoadError> {
+        let bytes = fs::read(path).map_err(LoadError::Io)?;
+        let text = String::from_utf8(bytes).map_err(|_| LoadError::UnknownFormat)?;
+        TrackerState::decode(&text)
+    }
+}
+
+impl<'a, T: EventMessenger> LimitTracker<'a, T> {
+    pub fn state(&self) -> TrackerState {
+        TrackerState {
+            value: self.value(),
+            max: self.max(),
+            reported: self.reported().map(|threshold| threshold.percent),
+        }
+    }
+
+    /// A tracker with the usage and reported threshold from `state`. Nothing is
+    /// sent until the next `set_value`, which only reports bands not reported
+    /// before the restart.
+    pub fn from_state(
+        messenger: &'a T,
+        policy: ThresholdPolicy,
+        state: TrackerState,
+    ) -> LimitTracker<'a, T> {
+        LimitTracker::with_policy(messenger, state.max, policy).resume(state.value, state.reported)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.state().save(path)
+    }
+
+    pub fn load(
+        messenger: &'a T,
+        policy: ThresholdPolicy,
+        path: impl AsRef<Path>,
+    ) -> Result<LimitTracker<'a, T>, LoadError> {
+        let state = TrackerState::load(path)?;
+        Ok(LimitTracker::from_state(messenger, policy, state))
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// Not a saved tracker at all.
+    UnknownFormat,
+    /// Saved by a version of this format we can't read.
+    UnsupportedVersion(u32),
+    /// The file ends before the checksum line.
+    Truncated,
+    ChecksumMismatch,
+    /// The checksum matched but a line doesn't parse, counting from 1.
+    Corrupt {
+        line: usize,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "can't read tracker state: {error}"),
+            LoadError::UnknownFormat => write!(f, "not a saved tracker state"),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "tracker state version {version} isn't supported")
+            }
+            LoadError::Truncated => write!(f, "tracker state is truncated"),
+            LoadError::ChecksumMismatch => write!(f, "tracker state checksum doesn't match"),
+            LoadError::Corrupt { line } => write!(f, "tracker state is corrupt at line {line}"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// Makes the rename into `path` durable by syncing the directory entry.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+// Only Unix lets a directory be opened and synced like this.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// FNV-1a, which is plenty to catch accidental damage.
+fn checksum(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoadError, TrackerState, checksum};
+    use crate::refcellt::{LimitTracker, Severity, ThresholdPolicy};
+    use crate::test_util::{Recorded, RecordingMessenger, TempFile};
+    use std::fs;
+    use std::io;
+    use std::thread;
+
+    fn state() -> TrackerState {
+        TrackerState {
+            value: 80,
+            max: 100,
+            reported: Some(75),
+        }
+    }
+
+    // Re-signs `body` so only the part under test is wrong.
+    fn with_checksum(body: &str) -> String {
+        format!("{body}checksum {:016x}\n", checksum(body))
+    }
+
+    #[test]
+    fn encode_and_decode_round_trip() {
+        for state in [
+            state(),
+            TrackerState {
+                value: usize::MAX,
+                max: 0,
+                reported: None,
+            },
+        ] {
+            assert_eq!(TrackerState::decode(&state.encode()).unwrap(), state);
+        }
+        // The example in the module docs.
+        assert_eq!(
+            state().encode(),
+            "limit-tracker v1\nvalue 80\nmax 100\nreported 75\nchecksum 85a2ba4e12067703\n"
+        );
+    }
+
+    #[test]
+    fn restored_tracker_does_not_repeat_warnings() {
+        let file = TempFile::new("restore.state");
+        let messenger = RecordingMessenger::new();
+        {
+            let mut tracker = LimitTracker::new(&messenger, 100);
+            tracker.set_value(80);
+            tracker.save(file.path()).unwrap();
+        }
+        messenger.assert_sent(&["Warning: You've used up over 75% of your quota!"]);
+
+        let mut tracker =
+            LimitTracker::load(&messenger, ThresholdPolicy::default(), file.path()).unwrap();
+        assert_eq!((tracker.value(), tracker.max()), (80, 100));
+        assert_eq!(tracker.reported().unwrap().percent, 75);
+        tracker.set_value(85);
+        messenger.assert_nothing_sent();
+        tracker.set_value(95);
+        messenger.assert_sent(&["Urgent warning: You've used up over 90% of your quota!"]);
+
+        tracker.save(file.path()).unwrap();
+        let policy = ThresholdPolicy::default().recovery("back under {threshold}%");
+        let mut tracker = LimitTracker::load(&messenger, policy, file.path()).unwrap();
+        tracker.set_value(10);
+        messenger.assert_sent(&["back under 90%"]);
+    }
+
+    #[test]
+    fn restoring_under_a_changed_policy() {
+        let messenger = RecordingMessenger::new();
+        let policy = ThresholdPolicy::empty()
+            .threshold(50, Severity::Warning, "half")
+            .threshold(90, Severity::Urgent, "nearly");
+        let mut tracker = LimitTracker::from_state(&messenger, policy, state());
+
+        // 75 is gone, so the highest threshold below it counts as reported.
+        assert_eq!(tracker.reported().unwrap().percent, 50);
+        tracker.set_value(80);
+        messenger.assert_nothing_sent();
+        tracker.set_value(90);
+        messenger.assert_sent(&["nearly"]);
+    }
+
+    #[test]
+    fn save_replaces_the_file_atomically() {
+        let file = TempFile::new("atomic.state");
+        fs::write(file.path(), "old contents").unwrap();
+
+        state().save(file.path()).unwrap();
+        assert_eq!(TrackerState::load(file.path()).unwrap(), state());
+
+        assert!(leftover_temp_files(&file).is_empty());
+    }
+
+    // Temporary files `save` left next to `file`.
+    fn leftover_temp_files(file: &TempFile) -> Vec<String> {
+        let name = file.path().file_name().unwrap().to_str().unwrap();
+        let prefix = format!("{name}.");
+        fs::read_dir(file.path().parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|entry| entry.starts_with(&prefix) && entry.ends_with(".tmp"))
+            .collect()
+    }
+
+    #[test]
+    fn concurrent_saves_each_use_their_own_temp_file() {
+        let file = TempFile::new("concurrent.state");
+        thread::scope(|s| {
+            for value in 0..8 {
+                let path = file.path();
+                s.spawn(move || {
+                    for _ in 0..20 {
+                        let state = TrackerState { value, ..state() };
+                        state.save(path).unwrap();
+                    }
+                });
+            }
+        });
+
+        let saved = TrackerState::load(file.path()).unwrap();
+        assert!(saved.value < 8);
+        assert!(leftover_temp_files(&file).is_empty());
+    }
+
+    #[test]
+    fn every_truncation_is_detected() {
+        let text = state().encode();
+        for len in 0..text.len() {
+            let result = TrackerState::decode(&text[..len]);
+            assert!(
+                matches!(result, Err(LoadError::Truncated)),
+                "{len} bytes: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let newer = state().encode().replacen("v1", "v2", 1);
+        assert!(matches!(
+            TrackerState::decode(&newer),
+            Err(LoadError::UnsupportedVersion(2))
+        ));
+        // Reported before the checksum, which a different version may not have.
+        assert!(matches!(
+            TrackerState::decode("limit-tracker v0\nvalue 80\n"),
+            Err(LoadError::UnsupportedVersion(0))
+        ));
+    }
+
+    #[test]
+    fn damage_is_detected() {
+        let changed = state().encode().replacen("value 80", "value 30", 1);
+        assert!(matches!(
+            TrackerState::decode(&changed),
+            Err(LoadError::ChecksumMismatch)
+        ));
+
+        let bad_field = with_checksum("limit-tracker v1\nvalue 80\nmax lots\nreported none\n");
+        assert!(matches!(
+            TrackerState::decode(&bad_field),
+            Err(LoadError::Corrupt { line: 3 })
+        ));
+        let extra = with_checksum("limit-tracker v1\nvalue 1\nmax 2\nreported none\nmore\n");
+        assert!(matches!(
+            TrackerState::decode(&extra),
+            Err(LoadError::Corrupt { line: 5 })
+        ));
+
+        for text in ["hello\n", "limit-tracker vX\n"] {
+            assert!(matches!(
+                TrackerState::decode(text),
+                Err(LoadError::UnknownFormat)
+            ));
+        }
+    }
+
+    #[test]
+    fn missing_file_is_an_io_error() {
+        let file = TempFile::new("missing.state");
+        match TrackerState::load(file.path()) {
+            Err(LoadError::Io(error)) => assert_eq!(error.kind(), io::ErrorKind::NotFound),
+            other => panic!("expected an I/O error, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! A `LimitTracker` that many threads can record usage against.
+//!
+//! The counter is a plain atomic, but every change is followed by a threshold
+//! check under a lock. The messenger runs inside that lock, which keeps band
+//! changes and the events they send in one order, so no threshold is reported twice
+//! for one crossing. It also means a slow messenger holds up every thread that
+//! records usage meanwhile.
+//!
+//! Threads racing each other can move the total past several thresholds between
+//! two checks. Unlike `LimitTracker`, which reports only the highest, every
+//! threshold crossed is reported exactly once, lowest first on the way up and
+//! highest first on the way down.
+//!
+//! A messenger that panics doesn't take the tracker down with it: the band was
+//! already moved, so the events it didn't deliver are lost, and later changes are
+//! checked as usual.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::refcellt::{EventMessenger, ThresholdPolicy};
+
+pub struct SharedLimitTracker<T: EventMessenger + Send + Sync> {
+    messenger: T,
+    value: AtomicUsize,
+    max: usize,
+    policy: ThresholdPolicy,
+    band: Mutex<Option<usize>>,
+}
+
+impl<T> SharedLimitTracker<T>
+where
+    T: EventMessenger + Send + Sync,
+{
+    pub fn new(messenger: T, max: usize) -> SharedLimitTracker<T> {
+        SharedLimitTracker::with_policy(messenger, max, ThresholdPolicy::default())
+    }
+
+    pub fn with_policy(messenger: T, max: usize, policy: ThresholdPolicy) -> SharedLimitTracker<T> {
+        SharedLimitTracker {
+            messenger,
+            value: AtomicUsize::new(0),
+            max,
+            policy,
+            band: Mutex::new(None),
+        }
+    }
+
+    /// Adds `amount` to the usage and returns the new total.
+    pub fn record(&self, amount: usize) -> usize {
+        let previous = self
+            .value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_add(amount))
+            })
+            .unwrap();
+        self.check();
+        previous.saturating_add(amount)
+    }
+
+    /// Gives back `amount` of usage and returns the new total.
+    ///
+    /// Releasing more than is in use is a bug. It panics in debug builds; release
+    /// builds stop the usage at 0.
+    pub fn release(&self, amount: usize) -> usize {
+        let previous = self
+            .value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_sub(amount))
+            })
+            .unwrap();
+        debug_assert!(
+            previous >= amount,
+            "released {amount} with only {previous} in use"
+        );
+        self.check();
+        previous.saturating_sub(amount)
+    }
+
+    pub fn set_value(&self, value: usize) {
+        self.value.store(value, Ordering::SeqCst);
+        self.check();
+    }
+
+    pub fn value(&self) -> usize {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    pub fn messenger(&self) -> &T {
+        &self.messenger
+    }
+
+    // The value is read again under the lock, so whichever thread gets here last
+    // sees the latest total; a thread whose own update was already handled finds
+    // nothing to do.
+    fn check(&self) {
+        let mut band = self.band.lock().unwrap_or_else(PoisonError::into_inner);
+        let value = self.value.load(Ordering::SeqCst);
+        for event in self.policy.crossings(&mut band, value, self.max) {
+            self.messenger.notify(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedLimitTracker;
+    use crate::refcellt::{Direction, Messenger, Severity, ThresholdPolicy};
+    use crate::test_util::RecordingEventMessenger;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn summary(recorder: &RecordingEventMessenger) -> Vec<(u32, Direction)> {
+        let events = recorder.events();
+        events.iter().map(|e| (e.threshold, e.direction)).collect()
+    }
+
+    #[test]
+    fn is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        struct Plain;
+        impl Messenger for Plain {
+            fn send(&self, _: &str) {}
+        }
+        assert_send_sync::<SharedLimitTracker<Plain>>();
+    }
+
+    #[test]
+    fn each_threshold_fires_once_under_contention() {
+        // Small steps, then steps big enough to pass several thresholds at once.
+        for step in [1, 250, 1_000] {
+            let tracker = Arc::new(SharedLimitTracker::new(
+                RecordingEventMessenger::new(),
+                8_000,
+            ));
+            let mut handles = vec![];
+
+            for _ in 0..8 {
+                let tracker = Arc::clone(&tracker);
+                let handle = thread::spawn(move || {
+                    for _ in 0..1_000 / step {
+                        tracker.record(step);
+                    }
+                });
+                handles.push(handle);
+            }
+
+            for handle in handles {
+                handle.join().unwrap();
+            }
+
+            assert_eq!(tracker.value(), 8_000);
+            assert_eq!(
+                summary(tracker.messenger()),
+                vec![
+                    (75, Direction::Rising),
+                    (90, Direction::Rising),
+                    (100, Direction::Rising),
+                ],
+                "step {step}"
+            );
+        }
+    }
+
+    #[test]
+    fn crossings_alternate_when_usage_goes_up_and_down() {
+        let policy = ThresholdPolicy::empty()
+            .threshold(50, Severity::Warning, "half")
+            .recovery("under half");
+        let tracker = Arc::new(SharedLimitTracker::with_policy(
+            RecordingEventMessenger::new(),
+            400,
+            policy,
+        ));
+        tracker.set_value(200);
+        let mut handles = vec![];
+
+        for i in 0..8 {
+            let tracker = Arc::clone(&tracker);
+            let handle = thread::spawn(move || {
+                for _ in 0..500 {
+                    if i % 2 == 0 {
+                        tracker.record(20);
+                        tracker.release(20);
+                    } else {
+                        tracker.release(20);
+                        tracker.record(20);
+                    }
+                }
+            });
+            handles.push(handle);
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // However the threads interleaved, the threshold is never reported twice
+        // in a row without a recovery in between.
+        let summary = summary(tracker.messenger());
+        assert_eq!(summary[0], (50, Direction::Rising));
+        assert!(
+            summary.windows(2).all(|pair| pair[0].1 != pair[1].1),
+            "{summary:?}"
+        );
+        assert_eq!(tracker.value(), 200);
+        assert_eq!(summary.last().unwrap().1, Direction::Rising);
+    }
+
+    #[test]
+    fn a_jump_reports_every_threshold_it_passes() {
+        let policy = ThresholdPolicy::default().recovery("under {threshold}%");
+        let tracker = SharedLimitTracker::with_policy(RecordingEventMessenger::new(), 100, policy);
+        tracker.record(95);
+        tracker.record(10);
+        tracker.set_value(10);
+        assert_eq!(
+            summary(tracker.messenger()),
+            vec![
+                (75, Direction::Rising),
+                (90, Direction::Rising),
+                (100, Direction::Rising),
+                (100, Direction::Falling),
+                (90, Direction::Falling),
+                (75, Direction::Falling),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_panicking_messenger_does_not_poison_the_tracker() {
+        // Fails on the first message only.
+        struct FailsOnce(Mutex<Vec<String>>);
+
+        impl Messenger for FailsOnce {
+            fn send(&self, msg: &str) {
+                let mut sent = self.0.lock().unwrap();
+                sent.push(msg.to_string());
+                if sent.len() == 1 {
+                    drop(sent);
+                    panic!("could not deliver {msg:?}");
+                }
+            }
+        }
+
+        let tracker = Arc::new(SharedLimitTracker::new(FailsOnce(Mutex::new(vec![])), 100));
+        let first = Arc::clone(&tracker);
+        assert!(thread::spawn(move || first.record(80)).join().is_err());
+
+        tracker.record(20);
+        tracker.release(50);
+        assert_eq!(tracker.value(), 50);
+        assert_eq!(
+            *tracker.messenger().0.lock().unwrap(),
+            vec![
+                "Warning: You've used up over 75% of your quota!",
+                "Urgent warning: You've used up over 90% of your quota!",
+                "Error: You are over your quota!",
+            ]
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "released 5 with only 3 in use")]
+    fn releasing_more_than_recorded_is_caught() {
+        let tracker = SharedLimitTracker::new(RecordingEventMessenger::new(), 100);
+        tracker.record(3);
+        tracker.release(5);
+    }
+}
@@ -0,0 +1,351 @@
+//! A `RefCell` that remembers where its active borrows were taken.
+//!
+//! A conflicting `RefCell` borrow panics with a bare `BorrowMutError`, which says
+//! nothing about who holds the other borrow. `TrackedRefCell` records the caller
+//! of each `borrow` and `borrow_mut` still alive, so its panics and errors can name
+//! both places.
+//!
+//! Tracking is on in debug builds, and in release builds with the
+//! `track-borrows` feature. Otherwise the cell is just a `RefCell` with the same
+//! API, and a conflict only knows where the failed borrow was attempted.
+
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+
+pub struct TrackedRefCell<T: ?Sized> {
+    #[cfg(any(debug_assertions, feature = "track-borrows"))]
+    borrows: RefCell<Vec<Option<BorrowSite>>>,
+    cell: RefCell<T>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowSite {
+    pub location: &'static Location<'static>,
+    pub mutable: bool,
+}
+
+impl fmt::Display for BorrowSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.mutable { "borrow_mut" } else { "borrow" };
+        write!(f, "{kind} at {}", self.location)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowConflict {
+    /// The borrow that failed.
+    pub attempted: BorrowSite,
+    /// An active borrow it conflicts with; `None` if tracking is off.
+    pub held: Option<BorrowSite>,
+}
+
+impl fmt::Display for BorrowConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The same wording as `RefCell`'s own panics.
+        if self.attempted.mutable {
+            write!(f, "already borrowed: {}", self.attempted)?;
+        } else {
+            write!(f, "already mutably borrowed: {}", self.attempted)?;
+        }
+        match self.held {
+            Some(held) => write!(f, " conflicts with {held}"),
+            None => write!(f, " conflicts with an earlier borrow"),
+        }
+    }
+}
+
+impl std::error::Error for BorrowConflict {}
+
+impl<T> TrackedRefCell<T> {
+    pub const fn new(value: T) -> TrackedRefCell<T> {
+        TrackedRefCell {
+            #[cfg(any(debug_assertions, feature = "track-borrows"))]
+            borrows: RefCell::new(Vec::new()),
+            cell: RefCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.cell.into_inner()
+    }
+
+    #[track_caller]
+    pub fn replace(&self, value: T) -> T {
+        std::mem::replace(&mut *self.borrow_mut(), value)
+    }
+}
+
+impl<T: ?Sized> TrackedRefCell<T> {
+    /// Like `RefCell::borrow`, but the panic names the conflicting borrow.
+    #[track_caller]
+    pub fn borrow(&self) -> TrackedRef<'_, T> {
+        match self.try_borrow() {
+            Ok(value) => value,
+            Err(conflict) => panic!("{conflict}"),
+        }
+    }
+
+    /// Like `RefCell::borrow_mut`, but the panic names the conflicting borrow.
+    #[track_caller]
+    pub fn borrow_mut(&self) -> TrackedRefMut<'_, T> {
+        match self.try_borrow_mut() {
+            Ok(value) => value,
+            Err(conflict) => panic!("{conflict}"),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_borrow(&self) -> Result<TrackedRef<'_, T>, BorrowConflict> {
+        let site = BorrowSite {
+            location: Location::caller(),
+            mutable: false,
+        };
+        match self.cell.try_borrow() {
+            Ok(value) => Ok(TrackedRef {
+                value,
+                _guard: self.track(site),
+            }),
+            Err(_) => Err(self.conflict(site)),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_borrow_mut(&self) -> Result<TrackedRefMut<'_, T>, BorrowConflict> {
+        let site = BorrowSite {
+            location: Location::caller(),
+            mutable: true,
+        };
+        match self.cell.try_borrow_mut() {
+            Ok(value) => Ok(TrackedRefMut {
+                value,
+                _guard: self.track(site),
+            }),
+            Err(_) => Err(self.conflict(site)),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.cell.get_mut()
+    }
+
+    /// Where the borrows alive right now were taken. Always empty if tracking is
+    /// off.
+    pub fn active_borrows(&self) -> Vec<BorrowSite> {
+        #[cfg(any(debug_assertions, feature = "track-borrows"))]
+        return self.borrows.borrow().iter().flatten().copied().collect();
+        #[cfg(not(any(debug_assertions, feature = "track-borrows")))]
+        return vec![];
+    }
+
+    #[cfg(any(debug_assertions, feature = "track-borrows"))]
+    fn track(&self, site: BorrowSite) -> Guard<'_> {
+        let mut borrows = self.borrows.borrow_mut();
+        let slot = match borrows.iter().position(Option::is_none) {
+            Some(slot) => {
+                borrows[slot] = Some(site);
+                slot
+            }
+            None => {
+                borrows.push(Some(site));
+                borrows.len() - 1
+            }
+        };
+        Guard {
+            borrows: &self.borrows,
+            slot,
+        }
+    }
+
+    #[cfg(not(any(debug_assertions, feature = "track-borrows")))]
+    fn track(&self, _: BorrowSite) -> Guard<'_> {
+        Guard(std::marker::PhantomData)
+    }
+
+    fn conflict(&self, attempted: BorrowSite) -> BorrowConflict {
+        // A shared borrow can only fail because of a mutable one.
+        let held = self
+            .active_borrows()
+            .into_iter()
+            .find(|held| held.mutable || attempted.mutable);
+        BorrowConflict { attempted, held }
+    }
+}
+
+impl<T: Default> Default for TrackedRefCell<T> {
+    fn default() -> TrackedRefCell<T> {
+        TrackedRefCell::new(T::default())
+    }
+}
+
+/// Prints like the `RefCell` it wraps.
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TrackedRefCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.cell, f)
+    }
+}
+
+// Frees the borrow's slot once it ends.
+#[cfg(any(debug_assertions, feature = "track-borrows"))]
+struct Guard<'b> {
+    borrows: &'b RefCell<Vec<Option<BorrowSite>>>,
+    slot: usize,
+}
+
+#[cfg(any(debug_assertions, feature = "track-borrows"))]
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.borrows.borrow_mut()[self.slot] = None;
+    }
+}
+
+#[cfg(not(any(debug_assertions, feature = "track-borrows")))]
+struct Guard<'b>(std::marker::PhantomData<&'b ()>);
+
+pub struct TrackedRef<'b, T: ?Sized> {
+    value: Ref<'b, T>,
+    _guard: Guard<'b>,
+}
+
+impl<T: ?Sized> Deref for TrackedRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TrackedRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+pub struct TrackedRefMut<'b, T: ?Sized> {
+    value: RefMut<'b, T>,
+    _guard: Guard<'b>,
+}
+
+impl<T: ?Sized> Deref for TrackedRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: ?Sized> DerefMut for TrackedRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TrackedRefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrackedRefCell;
+
+    #[test]
+    fn borrows_like_a_ref_cell() {
+        let cell = TrackedRefCell::new(vec![1, 2]);
+        {
+            let a = cell.borrow();
+            let b = cell.borrow();
+            assert_eq!(a.len() + b.len(), 4);
+            assert!(cell.try_borrow_mut().is_err());
+        }
+        cell.borrow_mut().push(3);
+        assert_eq!(cell.replace(vec![]), vec![1, 2, 3]);
+        assert_eq!(format!("{cell:?}"), "RefCell { value: [] }");
+        assert!(cell.active_borrows().is_empty());
+    }
+
+    #[cfg(any(debug_assertions, feature = "track-borrows"))]
+    #[test]
+    fn conflict_names_both_locations() {
+        let cell = TrackedRefCell::new(5);
+        let held_line = line!() + 1;
+        let held = cell.borrow_mut();
+        let attempted_line = line!() + 1;
+        let conflict = cell.try_borrow().unwrap_err();
+
+        assert!(!conflict.attempted.mutable);
+        assert_eq!(conflict.attempted.location.line(), attempted_line);
+        let held_site = conflict.held.unwrap();
+        assert!(held_site.mutable);
+        assert_eq!(held_site.location.line(), held_line);
+        assert_eq!(
+            conflict.to_string(),
+            format!(
+                "already mutably borrowed: borrow at {file}:{attempted_line}:{} \
+                 conflicts with borrow_mut at {file}:{held_line}:{}",
+                conflict.attempted.location.column(),
+                held_site.location.column(),
+                file = file!(),
+            )
+        );
+        drop(held);
+        assert!(cell.try_borrow().is_ok());
+    }
+
+    #[cfg(any(debug_assertions, feature = "track-borrows"))]
+    #[test]
+    fn borrow_mut_panics_with_both_locations() {
+        let cell = TrackedRefCell::new(String::new());
+        let reader_line = line!() + 1;
+        let _reader = cell.borrow();
+
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cell.borrow_mut().push('x');
+        }))
+        .unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("already borrowed: borrow_mut at "));
+        assert!(
+            message.contains(&format!(
+                "conflicts with borrow at {}:{reader_line}:",
+                file!()
+            )),
+            "{message}"
+        );
+    }
+
+    #[cfg(any(debug_assertions, feature = "track-borrows"))]
+    #[test]
+    fn ended_borrows_are_forgotten() {
+        let cell = TrackedRefCell::new(0);
+        let first = cell.borrow();
+        let second_line = line!() + 1;
+        let second = cell.borrow();
+        drop(first);
+        let sites = cell.active_borrows();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].location.line(), second_line);
+
+        drop(second);
+        assert!(cell.active_borrows().is_empty());
+        *cell.borrow_mut() += 1;
+        assert_eq!(cell.into_inner(), 1);
+    }
+
+    #[cfg(not(any(debug_assertions, feature = "track-borrows")))]
+    #[test]
+    fn untracked_conflicts_only_know_the_attempt() {
+        let cell = TrackedRefCell::new(0);
+        let _held = cell.borrow_mut();
+        let conflict = cell.try_borrow_mut().unwrap_err();
+        assert_eq!(conflict.held, None);
+        assert!(cell.active_borrows().is_empty());
+        assert!(
+            conflict
+                .to_string()
+                .ends_with("conflicts with an earlier borrow")
+        );
+    }
+}
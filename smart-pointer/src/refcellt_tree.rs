@@ -0,0 +1,372 @@
+//! Nested quotas, such as users inside teams inside an organization.
+//!
+//! Each `QuotaNode` has its own `LimitTracker`, and so its own thresholds and
+//! messages. The levels are a `weakt::Node` tree, so children are owned through
+//! `Rc` and parents are only reachable through `Weak`. Usage recorded on a node
+//! counts against every ancestor as well.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::refcellt::{EventMessenger, LimitTracker};
+use crate::tree_walk;
+use crate::weakt::{Node, TreeError};
+
+/// A handle to one level of a quota tree. Clones share the level.
+///
+/// The underlying `weakt::Node` isn't handed out, since moving it directly would
+/// skip moving its usage.
+pub struct QuotaNode<'a, T: EventMessenger> {
+    node: Rc<Node<Quota<'a, T>>>,
+}
+
+struct Quota<'a, T: EventMessenger> {
+    name: String,
+    tracker: RefCell<LimitTracker<'a, T>>,
+}
+
+impl<'a, T: EventMessenger> QuotaNode<'a, T> {
+    pub fn new(name: &str, tracker: LimitTracker<'a, T>) -> QuotaNode<'a, T> {
+        QuotaNode {
+            node: Node::new(Quota {
+                name: name.to_string(),
+                tracker: RefCell::new(tracker),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.node.value().name
+    }
+
+    pub fn parent(&self) -> Option<QuotaNode<'a, T>> {
+        self.node.parent().map(QuotaNode::wrap)
+    }
+
+    /// A snapshot of the children; no borrow is held once it returns.
+    pub fn children(&self) -> Vec<QuotaNode<'a, T>> {
+        self.node
+            .children()
+            .into_iter()
+            .map(QuotaNode::wrap)
+            .collect()
+    }
+
+    /// Usage recorded on `self` and its descendants.
+    pub fn value(&self) -> usize {
+        self.tracker().borrow().value()
+    }
+
+    pub fn max(&self) -> usize {
+        self.tracker().borrow().max()
+    }
+
+    /// Whether both handles are for the same level.
+    pub fn ptr_eq(&self, other: &QuotaNode<'a, T>) -> bool {
+        Rc::ptr_eq(&self.node, &other.node)
+    }
+
+    /// Moves `child` with its subtree under `self`. Its usage leaves the old
+    /// ancestors and is recorded on the new ones, which can send messages. Adding
+    /// a child that is already here does nothing.
+    pub fn add_child(&self, child: QuotaNode<'a, T>) -> Result<(), TreeError> {
+        if tree_walk::is_self_or_descendant_of(&self.node, &child.node) {
+            return Err(TreeError::WouldCreateCycle);
+        }
+        if child.parent().is_some_and(|parent| parent.ptr_eq(self)) {
+            return Ok(());
+        }
+        child.detach();
+        self.node.add_child(Rc::clone(&child.node));
+        self.record(child.value());
+        Ok(())
+    }
+
+    /// Takes `self` out of its parent; its usage no longer counts against its
+    /// former ancestors.
+    pub fn detach(&self) {
+        let Some(parent) = self.parent() else {
+            return;
+        };
+        self.node.detach();
+        parent.release(self.value());
+    }
+
+    /// The parent, the grandparent and so on up to the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = QuotaNode<'a, T>> + use<'a, T> {
+        self.node.ancestors().map(QuotaNode::wrap)
+    }
+
+    /// `self` first, the root last.
+    pub fn path_to_root(&self) -> Vec<QuotaNode<'a, T>> {
+        std::iter::once(self.clone())
+            .chain(self.ancestors())
+            .collect()
+    }
+
+    /// Adds `amount` to `self` and each ancestor, from `self` up, and lets every
+    /// level send its own messages.
+    pub fn record(&self, amount: usize) {
+        self.update_path(|value| value.saturating_add(amount));
+    }
+
+    /// Takes `amount` off `self` and each ancestor, which can send recovery
+    /// notices.
+    pub fn release(&self, amount: usize) {
+        self.update_path(|value| value.saturating_sub(amount));
+    }
+
+    /// How much more each level on the path to the root can take, `self` first.
+    pub fn headroom_path(&self) -> Vec<(QuotaNode<'a, T>, usize)> {
+        self.path_to_root()
+            .into_iter()
+            .map(|node| {
+                let headroom = node.max().saturating_sub(node.value());
+                (node, headroom)
+            })
+            .collect()
+    }
+
+    /// How much more can be recorded on `self` before some level is full.
+    pub fn headroom(&self) -> usize {
+        self.binding_constraint().1
+    }
+
+    /// The level with the least headroom, which is the one that limits `self`.
+    /// On a tie the level closest to the root wins, since raising only the lower
+    /// quota wouldn't help.
+    pub fn binding_constraint(&self) -> (QuotaNode<'a, T>, usize) {
+        self.headroom_path()
+            .into_iter()
+            .rev()
+            .min_by_key(|(_, headroom)| *headroom)
+            .expect("the path includes `self`")
+    }
+
+    fn wrap(node: Rc<Node<Quota<'a, T>>>) -> QuotaNode<'a, T> {
+        QuotaNode { node }
+    }
+
+    fn tracker(&self) -> &RefCell<LimitTracker<'a, T>> {
+        &self.node.value().tracker
+    }
+
+    // The tracker is let go before its messenger runs, so a messenger can look at
+    // the quotas it is told about.
+    fn update_path(&self, new_value: impl Fn(usize) -> usize) {
+        for node in self.path_to_root() {
+            let (event, messenger) = {
+                let mut tracker = node.tracker().borrow_mut();
+                let value = new_value(tracker.value());
+                (tracker.update(value), tracker.messenger())
+            };
+            if let Some(event) = event {
+                messenger.notify(&event);
+            }
+        }
+    }
+}
+
+// Derived `Clone` would want `T: Clone`.
+impl<T: EventMessenger> Clone for QuotaNode<'_, T> {
+    fn clone(&self) -> Self {
+        QuotaNode {
+            node: Rc::clone(&self.node),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quota, QuotaNode};
+    use crate::refcellt::{LimitTracker, Messenger, Severity, ThresholdPolicy};
+    use crate::test_util::{Recorded, RecordingMessenger};
+    use crate::weakt::{Node, TreeError};
+    use std::cell::{OnceCell, RefCell};
+    use std::rc::{Rc, Weak};
+
+    fn quota<'a>(
+        messenger: &'a RecordingMessenger,
+        name: &str,
+        max: usize,
+    ) -> QuotaNode<'a, RecordingMessenger> {
+        let policy = ThresholdPolicy::empty()
+            .threshold(80, Severity::Warning, &format!("{name} at {{percent}}%"))
+            .threshold(100, Severity::Error, &format!("{name} full"))
+            .recovery(&format!("{name} ok"));
+        QuotaNode::new(name, LimitTracker::with_policy(messenger, max, policy))
+    }
+
+    #[test]
+    fn usage_propagates_to_every_ancestor() {
+        let messenger = RecordingMessenger::new();
+        let org = quota(&messenger, "org", 1000);
+        let team = quota(&messenger, "team", 100);
+        let alice = quota(&messenger, "alice", 50);
+        let bob = quota(&messenger, "bob", 60);
+        org.add_child(team.clone()).unwrap();
+        team.add_child(alice.clone()).unwrap();
+        team.add_child(bob.clone()).unwrap();
+
+        alice.record(40);
+        messenger.assert_sent(&["alice at 80%"]);
+        bob.record(45);
+        messenger.assert_sent(&["team at 85%"]);
+        bob.record(5);
+        messenger.assert_sent(&["bob at 83%"]);
+        assert_eq!(
+            [&org, &team, &alice, &bob].map(|node| node.value()),
+            [90, 90, 40, 50]
+        );
+
+        bob.release(50);
+        messenger.assert_sent(&["bob ok", "team ok"]);
+        assert_eq!(team.value(), 40);
+    }
+
+    #[test]
+    fn headroom_and_binding_constraint() {
+        let messenger = RecordingMessenger::new();
+        let org = quota(&messenger, "org", 100);
+        let team = quota(&messenger, "team", 80);
+        let alice = quota(&messenger, "alice", 50);
+        let bob = quota(&messenger, "bob", 50);
+        org.add_child(team.clone()).unwrap();
+        team.add_child(alice.clone()).unwrap();
+        team.add_child(bob.clone()).unwrap();
+
+        alice.record(10);
+        let path: Vec<_> = alice
+            .headroom_path()
+            .into_iter()
+            .map(|(node, headroom)| (node.name().to_string(), headroom))
+            .collect();
+        assert_eq!(
+            path,
+            vec![
+                (String::from("alice"), 40),
+                (String::from("team"), 70),
+                (String::from("org"), 90),
+            ]
+        );
+        assert_eq!(alice.binding_constraint().0.name(), "alice");
+
+        // Bob's usage eats into the team quota Alice shares.
+        bob.record(45);
+        let (node, headroom) = alice.binding_constraint();
+        assert_eq!((node.name(), headroom), ("team", 25));
+        assert_eq!(alice.headroom(), 25);
+
+        // Usage outside the team only shows up at the org level.
+        let other = quota(&messenger, "other", 100);
+        org.add_child(other.clone()).unwrap();
+        other.record(30);
+        assert_eq!(alice.binding_constraint().0.name(), "org");
+        assert_eq!(alice.headroom(), 15);
+
+        // Ties go to the level closer to the root.
+        let equal = quota(&messenger, "equal", 15);
+        alice.add_child(equal.clone()).unwrap();
+        assert_eq!(equal.binding_constraint().0.name(), "org");
+    }
+
+    #[test]
+    fn moving_a_subtree_moves_its_usage() {
+        let messenger = RecordingMessenger::new();
+        let org = quota(&messenger, "org", 1000);
+        let red = quota(&messenger, "red", 100);
+        let blue = quota(&messenger, "blue", 100);
+        let alice = quota(&messenger, "alice", 100);
+        org.add_child(red.clone()).unwrap();
+        org.add_child(blue.clone()).unwrap();
+        red.add_child(alice.clone()).unwrap();
+
+        alice.record(85);
+        messenger.assert_sent(&["alice at 85%", "red at 85%"]);
+
+        blue.add_child(alice.clone()).unwrap();
+        messenger.assert_sent(&["red ok", "blue at 85%"]);
+        assert_eq!([red.value(), blue.value(), org.value()], [0, 85, 85]);
+        assert_eq!(red.children().len(), 0);
+        assert!(alice.parent().unwrap().ptr_eq(&blue));
+
+        assert_eq!(
+            alice.add_child(org.clone()).err(),
+            Some(TreeError::WouldCreateCycle)
+        );
+        assert_eq!(
+            alice.add_child(alice.clone()).err(),
+            Some(TreeError::WouldCreateCycle)
+        );
+
+        alice.detach();
+        assert_eq!([blue.value(), org.value()], [0, 0]);
+        messenger.assert_sent(&["blue ok"]);
+    }
+
+    #[test]
+    fn weak_parents_do_not_leak() {
+        let messenger = RecordingMessenger::new();
+        let (org_weak, team_weak) = {
+            let org = quota(&messenger, "org", 10);
+            let team = quota(&messenger, "team", 10);
+            org.add_child(team.clone()).unwrap();
+            team.record(5);
+            (Rc::downgrade(&org.node), Rc::downgrade(&team.node))
+        };
+        assert!(org_weak.upgrade().is_none());
+        assert!(team_weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn adding_a_child_again_changes_nothing() {
+        let messenger = RecordingMessenger::new();
+        let team = quota(&messenger, "team", 100);
+        let alice = quota(&messenger, "alice", 100);
+        let bob = quota(&messenger, "bob", 100);
+        team.add_child(alice.clone()).unwrap();
+        team.add_child(bob.clone()).unwrap();
+        alice.record(90);
+        messenger.assert_sent(&["alice at 90%", "team at 90%"]);
+
+        team.add_child(alice.clone()).unwrap();
+        messenger.assert_nothing_sent();
+        assert_eq!(team.value(), 90);
+        let names: Vec<_> = team
+            .children()
+            .iter()
+            .map(|child| child.name().to_string())
+            .collect();
+        assert_eq!(names, ["alice", "bob"]);
+    }
+
+    // Looks the quota up again while it is being told about it. A strong handle
+    // here wouldn't get past the drop check.
+    #[derive(Default)]
+    struct ReadsBack<'a> {
+        quota: OnceCell<Weak<Node<Quota<'a, ReadsBack<'a>>>>>,
+        seen: RefCell<Vec<(String, usize)>>,
+    }
+
+    impl Messenger for ReadsBack<'_> {
+        fn send(&self, msg: &str) {
+            let quota = QuotaNode::wrap(self.quota.get().unwrap().upgrade().unwrap());
+            self.seen
+                .borrow_mut()
+                .push((msg.to_string(), quota.headroom()));
+        }
+    }
+
+    #[test]
+    fn messengers_can_read_the_quotas() {
+        let messenger = ReadsBack::default();
+        let policy = ThresholdPolicy::empty().threshold(50, Severity::Warning, "half");
+        let team = QuotaNode::new("team", LimitTracker::new(&messenger, 100));
+        let alice = QuotaNode::new("alice", LimitTracker::with_policy(&messenger, 10, policy));
+        team.add_child(alice.clone()).unwrap();
+        messenger.quota.set(Rc::downgrade(&alice.node)).unwrap();
+
+        alice.record(8);
+        assert_eq!(*messenger.seen.borrow(), [(String::from("half"), 2)]);
+    }
+}
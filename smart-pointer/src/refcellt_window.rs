@@ -0,0 +1,374 @@
+//! Rate quotas for `LimitTracker`-style notifications.
+//!
+//! A `WindowedTracker` counts usage recorded through `record` over a moving span
+//! of time instead of taking an absolute value, and reports thresholds through the
+//! same `ThresholdPolicy` as `LimitTracker`.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::refcellt::{EventMessenger, ThresholdPolicy};
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How usage is counted against `max` per `length` of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    /// Usage since the start of the current window; windows follow each other
+    /// back to back.
+    Fixed { length: Duration },
+    /// Usage in the last `length`, exactly. Keeps one entry per `record`.
+    SlidingLog { length: Duration },
+    /// The current fixed window plus the part of the previous one that the last
+    /// `length` still overlaps, assuming its usage was spread evenly.
+    SlidingCounter { length: Duration },
+    /// Tokens used from a bucket of `max` tokens that refills at `max` per
+    /// `length`.
+    TokenBucket { length: Duration },
+}
+
+enum State {
+    Fixed {
+        start: Instant,
+        count: usize,
+    },
+    SlidingLog {
+        log: VecDeque<(Instant, usize)>,
+        total: usize,
+    },
+    SlidingCounter {
+        start: Instant,
+        previous: usize,
+        current: usize,
+    },
+    TokenBucket {
+        refilled_at: Instant,
+        used: usize,
+    },
+}
+
+pub struct WindowedTracker<'a, T: EventMessenger, C: Clock = SystemClock> {
+    messenger: &'a T,
+    clock: C,
+    window: Window,
+    max: usize,
+    policy: ThresholdPolicy,
+    band: Option<usize>,
+    state: State,
+}
+
+impl<'a, T, C> WindowedTracker<'a, T, C>
+where
+    T: EventMessenger,
+    C: Clock,
+{
+    pub fn new(
+        messenger: &'a T,
+        max: usize,
+        window: Window,
+        clock: C,
+    ) -> WindowedTracker<'a, T, C> {
+        WindowedTracker::with_policy(messenger, max, window, clock, ThresholdPolicy::default())
+    }
+
+    pub fn with_policy(
+        messenger: &'a T,
+        max: usize,
+        window: Window,
+        clock: C,
+        policy: ThresholdPolicy,
+    ) -> WindowedTracker<'a, T, C> {
+        let now = clock.now();
+        let state = match window {
+            Window::Fixed { .. } => State::Fixed {
+                start: now,
+                count: 0,
+            },
+            Window::SlidingLog { .. } => State::SlidingLog {
+                log: VecDeque::new(),
+                total: 0,
+            },
+            Window::SlidingCounter { .. } => State::SlidingCounter {
+                start: now,
+                previous: 0,
+                current: 0,
+            },
+            Window::TokenBucket { .. } => State::TokenBucket {
+                refilled_at: now,
+                used: 0,
+            },
+        };
+        WindowedTracker {
+            messenger,
+            clock,
+            window,
+            max,
+            policy,
+            band: None,
+            state,
+        }
+    }
+
+    /// Adds `amount` of usage now and notifies the messenger if that moved the
+    /// usage into a new band. `record(0)` just lets expired usage go, which can
+    /// send a recovery notice.
+    pub fn record(&mut self, amount: usize) {
+        let now = self.clock.now();
+        self.advance(now);
+        match &mut self.state {
+            State::Fixed { count, .. } => *count = count.saturating_add(amount),
+            State::SlidingLog { log, total } => {
+                if amount > 0 {
+                    log.push_back((now, amount));
+                    *total = total.saturating_add(amount);
+                }
+            }
+            State::SlidingCounter { current, .. } => *current = current.saturating_add(amount),
+            State::TokenBucket { used, .. } => *used = used.saturating_add(amount),
+        }
+
+        let usage = self.current_usage(now);
+        if let Some(event) = self.policy.update(&mut self.band, usage, self.max) {
+            self.messenger.notify(&event);
+        }
+    }
+
+    /// Usage counted against `max` right now. Doesn't notify.
+    pub fn usage(&mut self) -> usize {
+        let now = self.clock.now();
+        self.advance(now);
+        self.current_usage(now)
+    }
+
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    // Drops whatever usage has fallen out of the window by `now`.
+    fn advance(&mut self, now: Instant) {
+        match (&mut self.state, self.window) {
+            (State::Fixed { start, count }, Window::Fixed { length }) => {
+                let windows = elapsed_windows(*start, now, length);
+                if windows > 0 {
+                    *start += length * windows;
+                    *count = 0;
+                }
+            }
+            (State::SlidingLog { log, total }, Window::SlidingLog { length }) => {
+                while let Some(&(at, amount)) = log.front() {
+                    if now.duration_since(at) < length {
+                        break;
+                    }
+                    log.pop_front();
+                    *total = total.saturating_sub(amount);
+                }
+            }
+            (
+                State::SlidingCounter {
+                    start,
+                    previous,
+                    current,
+                },
+                Window::SlidingCounter { length },
+            ) => {
+                let windows = elapsed_windows(*start, now, length);
+                if windows > 0 {
+                    *previous = if windows == 1 { *current } else { 0 };
+                    *current = 0;
+                    *start += length * windows;
+                }
+            }
+            (State::TokenBucket { refilled_at, used }, Window::TokenBucket { length }) => {
+                if *used == 0 {
+                    *refilled_at = now;
+                    return;
+                }
+                // Whole tokens only; the time behind a partial token carries over.
+                let elapsed = now.duration_since(*refilled_at).as_nanos();
+                let refill = elapsed * self.max as u128 / length.as_nanos().max(1);
+                if refill >= *used as u128 {
+                    *used = 0;
+                    *refilled_at = now;
+                } else if refill > 0 {
+                    *used -= refill as usize;
+                    let spent = refill * length.as_nanos() / self.max as u128;
+                    *refilled_at += Duration::from_nanos(spent as u64);
+                }
+            }
+            _ => unreachable!("state always matches the window"),
+        }
+    }
+
+    fn current_usage(&self, now: Instant) -> usize {
+        match (&self.state, self.window) {
+            (State::Fixed { count, .. }, _) => *count,
+            (State::SlidingLog { total, .. }, _) => *total,
+            (
+                State::SlidingCounter {
+                    start,
+                    previous,
+                    current,
+                },
+                Window::SlidingCounter { length },
+            ) => {
+                let length = length.as_nanos().max(1);
+                let remaining = length.saturating_sub(now.duration_since(*start).as_nanos());
+                let carried = (*previous as u128 * remaining / length) as usize;
+                carried.saturating_add(*current)
+            }
+            (State::TokenBucket { used, .. }, _) => *used,
+            _ => unreachable!("state always matches the window"),
+        }
+    }
+}
+
+// How many whole windows of `length` have passed since `start`.
+fn elapsed_windows(start: Instant, now: Instant, length: Duration) -> u32 {
+    let windows = now.duration_since(start).as_nanos() / length.as_nanos().max(1);
+    u32::try_from(windows).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Window, WindowedTracker};
+    use crate::refcellt::{Severity, ThresholdPolicy};
+    use crate::test_util::{FakeClock, Recorded, RecordingMessenger};
+    use std::time::Duration;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn policy() -> ThresholdPolicy {
+        ThresholdPolicy::empty()
+            .threshold(50, Severity::Warning, "{value}/{max}")
+            .threshold(100, Severity::Error, "over at {value}")
+            .recovery("under {threshold}% at {value}")
+    }
+
+    #[test]
+    fn every_mode_sends_the_same_messages_for_a_burst() {
+        for window in [
+            Window::Fixed { length: MINUTE },
+            Window::SlidingLog { length: MINUTE },
+            Window::SlidingCounter { length: MINUTE },
+            Window::TokenBucket { length: MINUTE },
+        ] {
+            let messenger = RecordingMessenger::new();
+            let clock = FakeClock::new();
+            let mut tracker =
+                WindowedTracker::with_policy(&messenger, 10, window, &clock, policy());
+            for _ in 0..12 {
+                tracker.record(1);
+            }
+            assert_eq!(messenger.take(), ["5/10", "over at 10"], "{window:?}");
+            assert_eq!(tracker.usage(), 12, "{window:?}");
+        }
+    }
+
+    #[test]
+    fn fixed_window_resets_at_the_boundary() {
+        let messenger = RecordingMessenger::new();
+        let clock = FakeClock::new();
+        let window = Window::Fixed { length: MINUTE };
+        let mut tracker = WindowedTracker::with_policy(&messenger, 10, window, &clock, policy());
+
+        tracker.record(6);
+        clock.advance(Duration::from_secs(59));
+        tracker.record(4);
+        messenger.assert_sent(&["6/10", "over at 10"]);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tracker.usage(), 0);
+        tracker.record(1);
+        messenger.assert_sent(&["under 100% at 1"]);
+
+        // Skipping several windows lines up with the original boundaries.
+        clock.advance(MINUTE * 3 + Duration::from_secs(30));
+        tracker.record(5);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(tracker.usage(), 0);
+        messenger.assert_sent(&["5/10"]);
+    }
+
+    #[test]
+    fn sliding_log_forgets_each_entry_after_the_window() {
+        let messenger = RecordingMessenger::new();
+        let clock = FakeClock::new();
+        let window = Window::SlidingLog { length: MINUTE };
+        let mut tracker = WindowedTracker::with_policy(&messenger, 10, window, &clock, policy());
+
+        tracker.record(5);
+        clock.advance(Duration::from_secs(30));
+        tracker.record(5);
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(tracker.usage(), 10);
+
+        clock.advance(Duration::from_secs(1));
+        tracker.record(0);
+        assert_eq!(tracker.usage(), 5);
+        clock.advance(Duration::from_secs(30));
+        tracker.record(0);
+        messenger.assert_sent(&["5/10", "over at 10", "under 100% at 5", "under 50% at 0"]);
+    }
+
+    #[test]
+    fn sliding_counter_weights_the_previous_window() {
+        let messenger = RecordingMessenger::new();
+        let clock = FakeClock::new();
+        let window = Window::SlidingCounter { length: MINUTE };
+        let mut tracker = WindowedTracker::with_policy(&messenger, 10, window, &clock, policy());
+
+        tracker.record(8);
+        clock.advance(Duration::from_secs(90));
+        // Half of the last minute overlaps the previous window: 8 / 2 + 2.
+        tracker.record(2);
+        assert_eq!(tracker.usage(), 6);
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(tracker.usage(), 4);
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(tracker.usage(), 1);
+        clock.advance(MINUTE);
+        assert_eq!(tracker.usage(), 0);
+        messenger.assert_sent(&["8/10"]);
+    }
+
+    #[test]
+    fn token_bucket_refills_steadily() {
+        let messenger = RecordingMessenger::new();
+        let clock = FakeClock::new();
+        let window = Window::TokenBucket { length: MINUTE };
+        let mut tracker = WindowedTracker::with_policy(&messenger, 10, window, &clock, policy());
+
+        tracker.record(10);
+        // One token every 6 seconds; partial tokens aren't lost.
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(tracker.usage(), 9);
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(tracker.usage(), 8);
+        clock.advance(Duration::from_secs(30));
+        tracker.record(0);
+        assert_eq!(tracker.usage(), 3);
+
+        // A full bucket doesn't save up time for later.
+        clock.advance(MINUTE * 5);
+        assert_eq!(tracker.usage(), 0);
+        tracker.record(10);
+        messenger.assert_sent(&["over at 10", "under 100% at 3", "over at 10"]);
+    }
+}
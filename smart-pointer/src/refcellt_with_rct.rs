@@ -1,26 +1,56 @@
-use std::cell::RefCell;
+use std::fmt;
 use std::rc::Rc;
 
+use crate::dot::{self, Edge};
+use crate::refcellt_tracked::TrackedRefCell;
+
 #[derive(Debug)]
-enum List {
-    Cons(Rc<RefCell<i32>>, Rc<List>),
+pub enum List<T> {
+    Cons(Rc<TrackedRefCell<T>>, Rc<List<T>>),
     Nil,
 }
 
+/// Graphviz DOT for the lists starting at `roots`. The shared values are
+/// allocations of their own, so a value shared between lists is drawn once.
+pub fn to_dot<T: fmt::Debug>(roots: &[&Rc<List<T>>]) -> String {
+    dot::list_to_dot(roots, |graph, id, node| {
+        let List::Cons(value, rest) = node else {
+            return (String::from("Nil"), None);
+        };
+        let (value_id, seen) = graph.id(Rc::as_ptr(value));
+        graph.edge(id, value_id, Edge::Strong);
+        if !seen {
+            // A value mutably borrowed elsewhere can't be read right now.
+            let label = match value.try_borrow() {
+                Ok(value) => format!("{:?}", *value),
+                Err(_) => String::from("<borrowed>"),
+            };
+            graph.label(
+                value_id,
+                &label,
+                Rc::strong_count(value),
+                Rc::weak_count(value),
+            );
+        }
+        (String::from("Cons"), Some(Rc::clone(rest)))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::List::{Cons, Nil};
-    use std::cell::RefCell;
+    use crate::refcellt_tracked::TrackedRefCell;
+    use crate::test_util::LeakTracker;
     use std::rc::Rc;
 
     #[test]
     fn run() {
-        let value = Rc::new(RefCell::new(5));
+        let value = Rc::new(TrackedRefCell::new(5));
 
         let a = Rc::new(Cons(Rc::clone(&value), Rc::new(Nil)));
 
-        let b = Cons(Rc::new(RefCell::new(3)), Rc::clone(&a));
-        let c = Cons(Rc::new(RefCell::new(4)), Rc::clone(&a));
+        let b = Cons(Rc::new(TrackedRefCell::new(3)), Rc::clone(&a));
+        let c = Cons(Rc::new(TrackedRefCell::new(4)), Rc::clone(&a));
 
         *value.borrow_mut() += 10;
 
@@ -40,4 +70,99 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn string_payloads() {
+        let value = Rc::new(TrackedRefCell::new(String::from("shared")));
+        let a = Rc::new(Cons(Rc::clone(&value), Rc::new(Nil)));
+        let b = Cons(
+            Rc::new(TrackedRefCell::new(String::from("b"))),
+            Rc::clone(&a),
+        );
+
+        value.borrow_mut().push_str(" and mutated");
+
+        let Cons(_, b_tail) = &b else {
+            panic!("b is a Cons");
+        };
+        let Cons(a_value, _) = &**b_tail else {
+            panic!("b's tail is a Cons");
+        };
+        assert_eq!(*a_value.borrow(), "shared and mutated");
+    }
+
+    #[test]
+    fn shared_value_is_dropped_with_its_last_owner() {
+        let tracker = LeakTracker::new();
+        let value = Rc::new(TrackedRefCell::new(tracker.track(5)));
+        let a = Rc::new(Cons(Rc::clone(&value), Rc::new(Nil)));
+        let b = Cons(
+            Rc::new(TrackedRefCell::new(tracker.track(3))),
+            Rc::clone(&a),
+        );
+        let c = Cons(Rc::clone(&value), Rc::new(Nil));
+        drop(value);
+        drop(a);
+
+        drop(b);
+        assert_eq!(tracker.dropped(), 1);
+        // Replacing a value drops the old one right away.
+        let Cons(shared, _) = &c else {
+            panic!("c is a Cons");
+        };
+        *shared.borrow_mut() = tracker.track(6);
+        assert_eq!(tracker.dropped(), 2);
+        drop(c);
+        assert_eq!(tracker.dropped(), 3);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn to_dot_shows_shared_values() {
+        let value = Rc::new(TrackedRefCell::new(5));
+        let a = Rc::new(Cons(Rc::clone(&value), Rc::new(Nil)));
+        let b = Rc::new(Cons(Rc::new(TrackedRefCell::new(3)), Rc::clone(&a)));
+        let c = Rc::new(Cons(Rc::clone(&value), Rc::new(Nil)));
+        let _borrowed = value.borrow_mut();
+
+        assert_eq!(
+            super::to_dot(&[&b, &c]),
+            concat!(
+                "digraph {\n",
+                "    node [shape=box];\n",
+                "    n0 [label=\"Cons\\nstrong: 1, weak: 0\"];\n",
+                "    n1 [label=\"3\\nstrong: 1, weak: 0\"];\n",
+                "    n2 [label=\"Cons\\nstrong: 2, weak: 0\"];\n",
+                "    n3 [label=\"<borrowed>\\nstrong: 3, weak: 0\"];\n",
+                "    n4 [label=\"Nil\\nstrong: 1, weak: 0\"];\n",
+                "    n5 [label=\"Cons\\nstrong: 1, weak: 0\"];\n",
+                "    n6 [label=\"Nil\\nstrong: 1, weak: 0\"];\n",
+                "    n0 -> n1;\n",
+                "    n0 -> n2;\n",
+                "    n2 -> n3;\n",
+                "    n2 -> n4;\n",
+                "    n5 -> n3;\n",
+                "    n5 -> n6;\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[cfg(any(debug_assertions, feature = "track-borrows"))]
+    #[test]
+    fn borrow_conflict_names_the_holder() {
+        let value = Rc::new(TrackedRefCell::new(5));
+        let a = Cons(Rc::clone(&value), Rc::new(Nil));
+        let held_line = line!() + 1;
+        let reader = value.borrow();
+
+        let Cons(shared, _) = &a else {
+            panic!("a is a Cons");
+        };
+        let conflict = shared.try_borrow_mut().unwrap_err();
+        assert_eq!(conflict.held.unwrap().location.line(), held_line);
+        drop(reader);
+        *shared.borrow_mut() += 1;
+        assert_eq!(*value.borrow(), 6);
+    }
 }
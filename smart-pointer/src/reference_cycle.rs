@@ -1,15 +1,17 @@
+use crate::dot;
+use List::{Cons, Nil};
 use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
 use std::rc::Rc;
-use List::{Cons, Nil};
 
-#[derive(Debug)]
-enum List {
-    Cons(i32, RefCell<Rc<List>>),
+pub enum List<T> {
+    Cons(T, RefCell<Rc<List<T>>>),
     Nil,
 }
 
-impl List {
-    fn tail(&self) -> Option<&RefCell<Rc<List>>> {
+impl<T> List<T> {
+    pub fn tail(&self) -> Option<&RefCell<Rc<List<T>>>> {
         match self {
             Cons(_, item) => Some(item),
             Nil => None,
@@ -17,41 +19,343 @@ impl List {
     }
 }
 
+/// Where a cycle starts and how many nodes it goes around.
+#[derive(Debug)]
+pub struct Cycle<T> {
+    pub start: Rc<List<T>>,
+    /// Number of nodes between the head and `start`.
+    pub start_index: usize,
+    pub length: usize,
+}
+
+/// Floyd's tortoise and hare: finds the cycle reachable from `head`, if any,
+/// without allocating and without holding any `RefCell` borrow between steps.
+pub fn detect_cycle<T>(head: &Rc<List<T>>) -> Option<Cycle<T>> {
+    let mut slow = Rc::clone(head);
+    let mut fast = Rc::clone(head);
+    loop {
+        slow = next(&slow)?;
+        fast = next(&next(&fast)?)?;
+        if Rc::ptr_eq(&slow, &fast) {
+            break;
+        }
+    }
+
+    // The distance from the head to the cycle start equals the distance from the
+    // meeting point to the cycle start, so walk both at the same speed.
+    let mut start_index = 0;
+    slow = Rc::clone(head);
+    while !Rc::ptr_eq(&slow, &fast) {
+        slow = next(&slow)?;
+        fast = next(&fast)?;
+        start_index += 1;
+    }
+
+    let mut length = 1;
+    let mut probe = next(&slow)?;
+    while !Rc::ptr_eq(&probe, &slow) {
+        probe = next(&probe)?;
+        length += 1;
+    }
+
+    Some(Cycle {
+        start: slow,
+        start_index,
+        length,
+    })
+}
+
+fn next<T>(node: &Rc<List<T>>) -> Option<Rc<List<T>>> {
+    node.tail().map(|link| Rc::clone(&link.borrow()))
+}
+
+/// Graphviz DOT for the lists starting at `roots`, with any reference cycle, the
+/// nodes that can never be freed, drawn in red.
+pub fn to_dot<T: fmt::Debug>(roots: &[&Rc<List<T>>]) -> String {
+    dot::list_to_dot(roots, |_, _, node| match node {
+        // A tail that is mutably borrowed right now can't be followed, so the
+        // drawing stops at that node.
+        Cons(value, link) => (
+            format!("{value:?}"),
+            link.try_borrow().ok().map(|next| Rc::clone(&next)),
+        ),
+        Nil => (String::from("Nil"), None),
+    })
+}
+
+// A derived `Debug` follows the tail forever once the list is tied into a cycle.
+// Print the values in order instead, and stop at the first node seen twice. A tail
+// that is mutably borrowed right now can't be followed either, so that is where
+// the output stops.
+impl<T: fmt::Debug> fmt::Debug for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Cons(value, link) = self else {
+            return write!(f, "()");
+        };
+
+        let mut seen = HashSet::new();
+        seen.insert(self as *const List<T>);
+        write!(f, "({value:?}")?;
+
+        let mut next = link.try_borrow().map(|next| Rc::clone(&next));
+        loop {
+            let Ok(current) = next else {
+                write!(f, " <borrowed>")?;
+                break;
+            };
+            let Cons(value, link) = &*current else {
+                break;
+            };
+            if !seen.insert(Rc::as_ptr(&current)) {
+                write!(f, " ... <cycle back to node {value:?}>")?;
+                break;
+            }
+            write!(f, " {value:?}")?;
+            next = link.try_borrow().map(|next| Rc::clone(&next));
+        }
+        write!(f, ")")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::List::{Cons, Nil};
+    use super::{List, detect_cycle, next, to_dot};
+    use crate::test_util::{Leak, LeakTracker};
     use std::cell::RefCell;
     use std::rc::Rc;
 
     #[test]
     fn run() {
-        let a = Rc::new(Cons(5, RefCell::new(Rc::new(Nil))));
+        let leaks = LeakTracker::scope(|tracker| {
+            let a = tracker.watch(
+                "a",
+                Rc::new(Cons(tracker.track(5), RefCell::new(Rc::new(Nil)))),
+            );
 
-        assert_eq!(Rc::strong_count(&a), 1);
-        if let Some(link) = a.tail() {
-            let inner_rc = link.borrow();
-            let list = &**inner_rc;
-            assert!(matches!(*list, Nil));
-        }
+            assert_eq!(Rc::strong_count(&a), 1);
+            if let Some(link) = a.tail() {
+                let inner_rc = link.borrow();
+                let list = &**inner_rc;
+                assert!(matches!(*list, Nil));
+            }
+
+            let b = tracker.watch(
+                "b",
+                Rc::new(Cons(tracker.track(10), RefCell::new(Rc::clone(&a)))),
+            );
+            assert_eq!(Rc::strong_count(&a), 2);
+            assert_eq!(Rc::strong_count(&b), 1);
+            if let Some(link) = b.tail() {
+                let inner_rc = link.borrow();
+                // check if the two Rc pointers are same.
+                assert!(Rc::ptr_eq(&inner_rc, &a));
+            }
+
+            if let Some(link) = a.tail() {
+                *link.borrow_mut() = Rc::clone(&b);
+            }
+
+            assert_eq!(Rc::strong_count(&b), 2);
+            assert_eq!(Rc::strong_count(&a), 2);
+
+            // We have a cycle now. A derived `Debug` would overflow the stack here,
+            // the custom one stops when it comes back around.
+            assert_eq!(
+                format!("a next item = {:?}", a.tail()),
+                "a next item = Some(RefCell { value: (10 5 ... <cycle back to node 10>) })"
+            );
+        });
+
+        // `a` and `b` went out of scope, but each still keeps the other alive.
+        assert_eq!(
+            leaks,
+            vec![
+                Leak::Allocation {
+                    label: String::from("a"),
+                    strong: 1,
+                    weak: 0,
+                },
+                Leak::Allocation {
+                    label: String::from("b"),
+                    strong: 1,
+                    weak: 0,
+                },
+                Leak::Payload {
+                    label: String::from("5"),
+                },
+                Leak::Payload {
+                    label: String::from("10"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn string_payloads() {
+        let a = Rc::new(Cons(String::from("a"), RefCell::new(Rc::new(Nil))));
+        let b = Rc::new(Cons(String::from("b"), RefCell::new(Rc::new(Nil))));
 
-        let b = Rc::new(Cons(10, RefCell::new(Rc::clone(&a))));
-        assert_eq!(Rc::strong_count(&a), 2);
-        assert_eq!(Rc::strong_count(&b), 1);
-        if let Some(link) = b.tail() {
-            let inner_rc = link.borrow();
-            // check if the two Rc pointers are same.
-            assert!(Rc::ptr_eq(&inner_rc, &a));
+        let Some(link) = a.tail() else {
+            panic!("a is a Cons");
+        };
+        *link.borrow_mut() = Rc::clone(&b);
+
+        let Cons(value, _) = &**link.borrow() else {
+            panic!("a's tail is a Cons");
+        };
+        assert_eq!(value, "b");
+        assert_eq!(Rc::strong_count(&b), 2);
+    }
+
+    #[test]
+    fn breaking_the_cycle_drops_every_payload() {
+        let tracker = LeakTracker::new();
+        {
+            let a = tracker.watch(
+                "a",
+                Rc::new(Cons(tracker.track(5), RefCell::new(Rc::new(Nil)))),
+            );
+            let b = tracker.watch(
+                "b",
+                Rc::new(Cons(tracker.track(10), RefCell::new(Rc::clone(&a)))),
+            );
+            *a.tail().unwrap().borrow_mut() = Rc::clone(&b);
+            assert_eq!(tracker.dropped(), 0);
+
+            // Pointing `a` somewhere else drops its reference to `b` right away,
+            // but `b` is still owned by the local.
+            *a.tail().unwrap().borrow_mut() = Rc::new(Nil);
+            assert_eq!(tracker.dropped(), 0);
+            drop(b);
+            assert_eq!(tracker.dropped(), 1);
         }
+        assert_eq!(tracker.dropped(), 2);
+        tracker.assert_no_leaks();
+    }
 
-        if let Some(link) = a.tail() {
-            *link.borrow_mut() = Rc::clone(&b);
+    // Builds `values` front to back and points the last node at `values[back_to]`.
+    fn cyclic_list(values: &[i32], back_to: usize) -> Rc<List<i32>> {
+        let nodes: Vec<_> = values
+            .iter()
+            .map(|&v| Rc::new(Cons(v, RefCell::new(Rc::new(Nil)))))
+            .collect();
+        for pair in nodes.windows(2) {
+            *pair[0].tail().unwrap().borrow_mut() = Rc::clone(&pair[1]);
         }
+        *nodes.last().unwrap().tail().unwrap().borrow_mut() = Rc::clone(&nodes[back_to]);
+        Rc::clone(&nodes[0])
+    }
 
-        assert_eq!(Rc::strong_count(&b), 2);
-        assert_eq!(Rc::strong_count(&a), 2);
+    #[test]
+    fn detect_cycle_finds_start_and_length() {
+        let list = cyclic_list(&[1, 2, 3, 4, 5, 6], 2);
+        let cycle = detect_cycle(&list).unwrap();
+        assert_eq!(cycle.start_index, 2);
+        assert_eq!(cycle.length, 4);
+        assert!(matches!(*cycle.start, Cons(3, _)));
+
+        let self_loop = cyclic_list(&[7], 0);
+        let cycle = detect_cycle(&self_loop).unwrap();
+        assert_eq!((cycle.start_index, cycle.length), (0, 1));
+        assert!(Rc::ptr_eq(&cycle.start, &self_loop));
+    }
+
+    #[test]
+    fn detect_cycle_on_acyclic_lists() {
+        let nil: Rc<List<i32>> = Rc::new(Nil);
+        assert!(detect_cycle(&nil).is_none());
+
+        let b = Rc::new(Cons(2, RefCell::new(Rc::clone(&nil))));
+        let a = Rc::new(Cons(1, RefCell::new(Rc::clone(&b))));
+        assert!(detect_cycle(&a).is_none());
+        assert_eq!(format!("{a:?}"), "(1 2)");
+        assert_eq!(format!("{nil:?}"), "()");
+    }
+
+    #[test]
+    fn debug_stops_at_the_cycle() {
+        let list = cyclic_list(&[1, 2, 3, 4, 5], 1);
+        assert_eq!(
+            format!("{list:?}"),
+            "(1 2 3 4 5 ... <cycle back to node 2>)"
+        );
+    }
+
+    #[test]
+    fn to_dot_highlights_the_cycle() {
+        let list = cyclic_list(&[1, 2, 3], 1);
+        let other = Rc::new(Cons(0, RefCell::new(Rc::new(Nil))));
+
+        assert_eq!(
+            to_dot(&[&list, &other]),
+            concat!(
+                "digraph {\n",
+                "    node [shape=box];\n",
+                "    n0 [label=\"1\\nstrong: 1, weak: 0\"];\n",
+                "    n1 [label=\"2\\nstrong: 2, weak: 0\", color=red];\n",
+                "    n2 [label=\"3\\nstrong: 1, weak: 0\", color=red];\n",
+                "    n3 [label=\"0\\nstrong: 1, weak: 0\"];\n",
+                "    n4 [label=\"Nil\\nstrong: 1, weak: 0\"];\n",
+                "    n0 -> n1;\n",
+                "    n1 -> n2 [color=red];\n",
+                "    n2 -> n1 [color=red];\n",
+                "    n3 -> n4;\n",
+                "}\n",
+            )
+        );
+
+        let self_loop = cyclic_list(&[7], 0);
+        assert_eq!(
+            to_dot(&[&self_loop]),
+            concat!(
+                "digraph {\n",
+                "    node [shape=box];\n",
+                "    n0 [label=\"7\\nstrong: 2, weak: 0\", color=red];\n",
+                "    n0 -> n0 [color=red];\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn to_dot_stops_at_a_tail_borrowed_elsewhere() {
+        let list = cyclic_list(&[1, 2, 3], 0);
+        let _replacing = list.tail().unwrap().borrow_mut();
 
-        // Uncomment the next line to see that we have a cycle;
-        // it will overflow the stack.
-        // println!("a next item = {:?}", a.tail());
+        assert_eq!(
+            to_dot(&[&list]),
+            concat!(
+                "digraph {\n",
+                "    node [shape=box];\n",
+                "    n0 [label=\"1\\nstrong: 2, weak: 0\"];\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn debug_stops_at_a_tail_borrowed_elsewhere() {
+        let list = cyclic_list(&[1, 2, 3], 0);
+        let second = next(&list).unwrap();
+        {
+            let _replacing = second.tail().unwrap().borrow_mut();
+            assert_eq!(format!("{list:?}"), "(1 2 <borrowed>)");
+        }
+        {
+            let _replacing = list.tail().unwrap().borrow_mut();
+            assert_eq!(format!("{list:?}"), "(1 <borrowed>)");
+        }
+        assert_eq!(format!("{list:?}"), "(1 2 3 ... <cycle back to node 1>)");
+    }
+
+    #[test]
+    fn cycles_can_be_printed() {
+        let list = cyclic_list(&[1, 2], 1);
+        let cycle = detect_cycle(&list).unwrap();
+        assert_eq!(
+            format!("{cycle:?}"),
+            "Cycle { start: (2 ... <cycle back to node 2>), start_index: 1, length: 1 }"
+        );
     }
 }
@@ -0,0 +1,549 @@
+//! Test helpers: leak checking for the `Rc` examples in this crate, a clock for the
+//! windowed quotas, temporary files, and messengers that record what they were
+//! sent.
+//!
+//! Wrap payloads with [`LeakTracker::track`] and register the `Rc` allocations you care
+//! about with [`LeakTracker::watch`]. Anything still alive when the tracker is checked
+//! is reported together with its strong and weak counts.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::refcellt::{EventMessenger, Messenger, QuotaEvent};
+use crate::refcellt_window::Clock;
+
+// Reports the (strong, weak) counts of a watched allocation.
+type Counts = Box<dyn Fn() -> (usize, usize)>;
+
+#[derive(Default)]
+pub struct LeakTracker {
+    payloads: RefCell<Vec<(String, Rc<Cell<bool>>)>>,
+    allocations: RefCell<Vec<(String, Counts)>>,
+    drops: Rc<Cell<usize>>,
+}
+
+impl LeakTracker {
+    pub fn new() -> LeakTracker {
+        LeakTracker::default()
+    }
+
+    /// Runs `f` with a fresh tracker and returns whatever it left alive.
+    pub fn scope<F: FnOnce(&LeakTracker)>(f: F) -> Vec<Leak> {
+        let tracker = LeakTracker::new();
+        f(&tracker);
+        tracker.leaks()
+    }
+
+    /// Wraps `value` so the tracker notices when it is dropped.
+    pub fn track<T: fmt::Debug>(&self, value: T) -> Tracked<T> {
+        let alive = Rc::new(Cell::new(true));
+        self.payloads
+            .borrow_mut()
+            .push((format!("{value:?}"), Rc::clone(&alive)));
+        Tracked {
+            value,
+            alive,
+            drops: Rc::clone(&self.drops),
+        }
+    }
+
+    /// Registers the allocation behind `rc` and hands it back.
+    ///
+    /// Only a `Weak` is kept, so watching does not change the strong count.
+    pub fn watch<T: 'static>(&self, label: impl Into<String>, rc: Rc<T>) -> Rc<T> {
+        let weak = Rc::downgrade(&rc);
+        let counts = move || match weak.strong_count() {
+            0 => (0, 0),
+            // Don't count the `Weak` held by the tracker itself.
+            strong => (strong, Weak::weak_count(&weak) - 1),
+        };
+        self.allocations
+            .borrow_mut()
+            .push((label.into(), Box::new(counts)));
+        rc
+    }
+
+    /// How many tracked payloads have been dropped so far.
+    pub fn dropped(&self) -> usize {
+        self.drops.get()
+    }
+
+    pub fn leaks(&self) -> Vec<Leak> {
+        let mut leaks = Vec::new();
+        for (label, counts) in self.allocations.borrow().iter() {
+            let (strong, weak) = counts();
+            if strong > 0 {
+                leaks.push(Leak::Allocation {
+                    label: label.clone(),
+                    strong,
+                    weak,
+                });
+            }
+        }
+        for (label, alive) in self.payloads.borrow().iter() {
+            if alive.get() {
+                leaks.push(Leak::Payload {
+                    label: label.clone(),
+                });
+            }
+        }
+        leaks
+    }
+
+    #[track_caller]
+    pub fn assert_no_leaks(&self) {
+        let leaks = self.leaks();
+        if !leaks.is_empty() {
+            let report: Vec<String> = leaks.iter().map(Leak::to_string).collect();
+            panic!("{} leak(s):\n  {}", leaks.len(), report.join("\n  "));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Leak {
+    /// A watched `Rc` allocation that still has strong references.
+    Allocation {
+        label: String,
+        strong: usize,
+        weak: usize,
+    },
+    /// A tracked payload that was never dropped.
+    Payload { label: String },
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Leak::Allocation {
+                label,
+                strong,
+                weak,
+            } => write!(f, "allocation `{label}` (strong = {strong}, weak = {weak})"),
+            Leak::Payload { label } => write!(f, "payload {label}"),
+        }
+    }
+}
+
+/// A payload created by [`LeakTracker::track`]; it behaves like the wrapped value.
+pub struct Tracked<T> {
+    value: T,
+    alive: Rc<Cell<bool>>,
+    drops: Rc<Cell<usize>>,
+}
+
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        self.alive.set(false);
+        self.drops.set(self.drops.get() + 1);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Tracked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<T: PartialEq> PartialEq for Tracked<T> {
+    fn eq(&self, other: &Tracked<T>) -> bool {
+        self.value == other.value
+    }
+}
+
+/// A `Clock` that only moves when `advance` is called.
+pub struct FakeClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl FakeClock {
+    pub fn new() -> FakeClock {
+        FakeClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> FakeClock {
+        FakeClock::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+/// A path in the temp directory, named after the process and `name`, that is
+/// removed when the `TempFile` is dropped. Nothing is created up front.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> TempFile {
+        let path =
+            std::env::temp_dir().join(format!("smart-pointer-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        TempFile(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// The assertions shared by the recording messengers.
+///
+/// Each `assert_*` method checks the messages sent since the previous assertion,
+/// then forgets them, so a test can check a few steps one after another.
+pub trait Recorded {
+    /// Returns the messages not yet checked and forgets them.
+    fn take(&self) -> Vec<String>;
+
+    /// Exactly `expected` was sent, in this order.
+    #[track_caller]
+    fn assert_sent(&self, expected: &[&str]) {
+        let actual = self.take();
+        if actual != expected {
+            panic!("expected messages {expected:?}, but got {actual:?}");
+        }
+    }
+
+    #[track_caller]
+    fn assert_nothing_sent(&self) {
+        self.assert_sent(&[]);
+    }
+
+    /// At least one message matching `predicate` was sent.
+    #[track_caller]
+    fn assert_sent_matching<P: FnMut(&str) -> bool>(&self, mut predicate: P) {
+        let actual = self.take();
+        if !actual.iter().any(|msg| predicate(msg)) {
+            panic!("no message matched, got {actual:?}");
+        }
+    }
+}
+
+/// A `Messenger` that keeps everything it is sent; see `Recorded` for the
+/// assertions.
+#[derive(Default)]
+pub struct RecordingMessenger {
+    messages: RefCell<Vec<String>>,
+}
+
+impl RecordingMessenger {
+    pub fn new() -> RecordingMessenger {
+        RecordingMessenger::default()
+    }
+
+    /// The messages not yet checked by an assertion.
+    pub fn messages(&self) -> Vec<String> {
+        self.messages.borrow().clone()
+    }
+}
+
+impl Recorded for RecordingMessenger {
+    fn take(&self) -> Vec<String> {
+        self.messages.take()
+    }
+}
+
+impl Messenger for RecordingMessenger {
+    fn send(&self, msg: &str) {
+        self.messages.borrow_mut().push(String::from(msg));
+    }
+}
+
+/// A `RecordingMessenger` that can be shared between threads.
+///
+/// Messages from different threads are kept in the order they arrived.
+#[derive(Default)]
+pub struct SyncRecordingMessenger {
+    messages: Mutex<Vec<String>>,
+}
+
+impl SyncRecordingMessenger {
+    pub fn new() -> SyncRecordingMessenger {
+        SyncRecordingMessenger::default()
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+impl Recorded for SyncRecordingMessenger {
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+}
+
+impl Messenger for SyncRecordingMessenger {
+    fn send(&self, msg: &str) {
+        self.messages.lock().unwrap().push(String::from(msg));
+    }
+}
+
+/// An `EventMessenger` that keeps the whole `QuotaEvent`s, for tests that check
+/// more than the message. It can be shared between threads.
+///
+/// The `Recorded` assertions look at the events' messages.
+#[derive(Default)]
+pub struct RecordingEventMessenger {
+    events: Mutex<Vec<QuotaEvent>>,
+}
+
+impl RecordingEventMessenger {
+    pub fn new() -> RecordingEventMessenger {
+        RecordingEventMessenger::default()
+    }
+
+    /// The events not yet checked or taken.
+    pub fn events(&self) -> Vec<QuotaEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Returns the events not yet checked or taken and forgets them.
+    pub fn take_events(&self) -> Vec<QuotaEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+impl Recorded for RecordingEventMessenger {
+    fn take(&self) -> Vec<String> {
+        self.take_events()
+            .into_iter()
+            .map(|event| event.message)
+            .collect()
+    }
+}
+
+impl EventMessenger for RecordingEventMessenger {
+    fn notify(&self, event: &QuotaEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+/// A `Messenger` whose delivery fails by panicking, for testing how callers cope.
+///
+/// `failing_after(n)` lets the first `n` messages through; they are recorded like
+/// with `RecordingMessenger`.
+pub struct FailingMessenger {
+    delivered: RecordingMessenger,
+    remaining: Cell<usize>,
+}
+
+impl FailingMessenger {
+    /// Fails on the first message.
+    pub fn new() -> FailingMessenger {
+        FailingMessenger::failing_after(0)
+    }
+
+    pub fn failing_after(n: usize) -> FailingMessenger {
+        FailingMessenger {
+            delivered: RecordingMessenger::new(),
+            remaining: Cell::new(n),
+        }
+    }
+
+    /// The messages that went through before the failure.
+    pub fn delivered(&self) -> &RecordingMessenger {
+        &self.delivered
+    }
+}
+
+impl Default for FailingMessenger {
+    fn default() -> FailingMessenger {
+        FailingMessenger::new()
+    }
+}
+
+impl Messenger for FailingMessenger {
+    fn send(&self, msg: &str) {
+        match self.remaining.get() {
+            0 => panic!("FailingMessenger: failed to deliver {msg:?}"),
+            n => {
+                self.remaining.set(n - 1);
+                self.delivered.send(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FailingMessenger, Leak, LeakTracker, Recorded, RecordingEventMessenger, RecordingMessenger,
+        SyncRecordingMessenger,
+    };
+    use crate::refcellt::{Direction, LimitTracker, Messenger, Severity};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn freed_values_are_not_reported() {
+        let leaks = LeakTracker::scope(|tracker| {
+            let value = tracker.watch("value", Rc::new(tracker.track(1)));
+            let _other = Rc::clone(&value);
+            assert_eq!(tracker.leaks().len(), 2);
+        });
+        assert!(leaks.is_empty());
+    }
+
+    #[test]
+    fn forgotten_values_are_reported_with_counts() {
+        let tracker = LeakTracker::new();
+        let value = tracker.watch("value", Rc::new(tracker.track("payload")));
+        let _weak = Rc::downgrade(&value);
+        std::mem::forget(Rc::clone(&value));
+        drop(value);
+
+        assert_eq!(tracker.dropped(), 0);
+        assert_eq!(
+            tracker.leaks(),
+            vec![
+                Leak::Allocation {
+                    label: String::from("value"),
+                    strong: 1,
+                    weak: 1,
+                },
+                Leak::Payload {
+                    label: String::from("\"payload\""),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "allocation `value` (strong = 1, weak = 0)")]
+    fn assert_no_leaks_panics_with_a_report() {
+        let tracker = LeakTracker::new();
+        std::mem::forget(tracker.watch("value", Rc::new(5)));
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn recording_messenger_with_limit_tracker() {
+        let messenger = RecordingMessenger::new();
+        let mut tracker = LimitTracker::new(&messenger, 100);
+
+        tracker.set_value(10);
+        messenger.assert_nothing_sent();
+        tracker.set_value(80);
+        tracker.set_value(95);
+        assert_eq!(messenger.messages().len(), 2);
+        messenger.assert_sent(&[
+            "Warning: You've used up over 75% of your quota!",
+            "Urgent warning: You've used up over 90% of your quota!",
+        ]);
+        tracker.set_value(100);
+        messenger.assert_sent_matching(|msg| msg.starts_with("Error"));
+        messenger.assert_nothing_sent();
+    }
+
+    #[test]
+    #[should_panic(expected = r#"expected messages ["a"], but got ["a", "b"]"#)]
+    fn assert_sent_reports_the_difference() {
+        let messenger = RecordingMessenger::new();
+        messenger.send("a");
+        messenger.send("b");
+        messenger.assert_sent(&["a"]);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"no message matched, got ["a"]"#)]
+    fn assert_sent_matching_reports_what_was_sent() {
+        let messenger = RecordingMessenger::new();
+        messenger.send("a");
+        messenger.assert_sent_matching(|msg| msg == "b");
+    }
+
+    #[test]
+    fn failing_messenger_fails_after_n_messages() {
+        let messenger = FailingMessenger::failing_after(1);
+        let mut tracker = LimitTracker::new(&messenger, 100);
+
+        tracker.set_value(80);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| tracker.set_value(100)));
+        assert!(result.is_err());
+        messenger
+            .delivered()
+            .assert_sent(&["Warning: You've used up over 75% of your quota!"]);
+    }
+
+    #[test]
+    fn sync_recording_messenger_across_threads() {
+        let messenger = Arc::new(SyncRecordingMessenger::new());
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let messenger = Arc::clone(&messenger);
+                thread::spawn(move || messenger.send(&format!("thread {i}")))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut messages = messenger.messages();
+        messages.sort();
+        assert_eq!(messages, ["thread 0", "thread 1", "thread 2", "thread 3"]);
+        messenger.assert_sent_matching(|msg| msg == "thread 2");
+        messenger.assert_nothing_sent();
+    }
+
+    #[test]
+    fn recording_event_messenger_keeps_the_events() {
+        let messenger = RecordingEventMessenger::new();
+        let mut tracker = LimitTracker::new(&messenger, 100);
+
+        tracker.set_value(80);
+        let events = messenger.take_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            (events[0].severity, events[0].threshold, events[0].direction),
+            (Severity::Warning, 75, Direction::Rising)
+        );
+        tracker.set_value(100);
+        messenger.assert_sent(&["Error: You are over your quota!"]);
+        assert!(messenger.events().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+//! Walks shared by `weakt::Node` and `weakt_sync::SyncNode`.
+//!
+//! Both trees hand out their nodes as a pointer (`Rc` or `Arc`) and link them the
+//! same way, so the iterators and the walks built on them only need to follow a
+//! node's parent and copy out its children.
+
+use std::collections::VecDeque;
+
+pub trait TreeLinks: Clone {
+    fn parent_link(&self) -> Option<Self>;
+    /// A snapshot; no borrow or lock is held once it returns.
+    fn child_links(&self) -> Vec<Self>;
+    fn same_node(&self, other: &Self) -> bool;
+}
+
+// The iterators copy out the children they need and keep no borrow or lock between
+// calls to `next`, so the tree can be changed while iterating. Changes only show
+// up for nodes the iterator hasn't expanded yet.
+
+pub struct Ancestors<N> {
+    next: Option<N>,
+}
+
+impl<N: TreeLinks> Ancestors<N> {
+    pub(crate) fn of(node: &N) -> Ancestors<N> {
+        Ancestors {
+            next: node.parent_link(),
+        }
+    }
+}
+
+impl<N: TreeLinks> Iterator for Ancestors<N> {
+    type Item = N;
+
+    fn next(&mut self) -> Option<N> {
+        let node = self.next.take()?;
+        self.next = node.parent_link();
+        Some(node)
+    }
+}
+
+pub struct Preorder<N> {
+    stack: Vec<N>,
+}
+
+impl<N: TreeLinks> Preorder<N> {
+    pub(crate) fn new(node: &N) -> Preorder<N> {
+        Preorder {
+            stack: vec![node.clone()],
+        }
+    }
+}
+
+impl<N: TreeLinks> Iterator for Preorder<N> {
+    type Item = N;
+
+    fn next(&mut self) -> Option<N> {
+        let node = self.stack.pop()?;
+        self.stack.extend(node.child_links().into_iter().rev());
+        Some(node)
+    }
+}
+
+pub struct Postorder<N> {
+    // The flag is set once the node's children have been pushed.
+    stack: Vec<(N, bool)>,
+}
+
+impl<N: TreeLinks> Postorder<N> {
+    pub(crate) fn new(node: &N) -> Postorder<N> {
+        Postorder {
+            stack: vec![(node.clone(), false)],
+        }
+    }
+}
+
+impl<N: TreeLinks> Iterator for Postorder<N> {
+    type Item = N;
+
+    fn next(&mut self) -> Option<N> {
+        loop {
+            let (node, expanded) = self.stack.pop()?;
+            if expanded {
+                return Some(node);
+            }
+            let children = node.child_links();
+            self.stack.push((node, true));
+            self.stack
+                .extend(children.into_iter().rev().map(|child| (child, false)));
+        }
+    }
+}
+
+pub struct BreadthFirst<N> {
+    queue: VecDeque<N>,
+}
+
+impl<N: TreeLinks> BreadthFirst<N> {
+    pub(crate) fn new(node: &N) -> BreadthFirst<N> {
+        BreadthFirst {
+            queue: VecDeque::from([node.clone()]),
+        }
+    }
+}
+
+impl<N: TreeLinks> Iterator for BreadthFirst<N> {
+    type Item = N;
+
+    fn next(&mut self) -> Option<N> {
+        let node = self.queue.pop_front()?;
+        self.queue.extend(node.child_links());
+        Some(node)
+    }
+}
+
+pub(crate) fn root<N: TreeLinks>(node: &N) -> N {
+    Ancestors::of(node).last().unwrap_or_else(|| node.clone())
+}
+
+/// The root first, `node` last.
+pub(crate) fn path_from_root<N: TreeLinks>(node: &N) -> Vec<N> {
+    let mut path: Vec<_> = Ancestors::of(node).collect();
+    path.reverse();
+    path.push(node.clone());
+    path
+}
+
+pub(crate) fn leaves<N: TreeLinks>(node: &N) -> Vec<N> {
+    Preorder::new(node)
+        .filter(|node| node.child_links().is_empty())
+        .collect()
+}
+
+/// Number of edges on the longest path down to a leaf.
+pub(crate) fn height<N: TreeLinks>(node: &N) -> usize {
+    let mut height = 0;
+    let mut level = vec![node.clone()];
+    loop {
+        level = level.iter().flat_map(TreeLinks::child_links).collect();
+        if level.is_empty() {
+            return height;
+        }
+        height += 1;
+    }
+}
+
+pub(crate) fn is_parent_of<N: TreeLinks>(parent: &N, child: &N) -> bool {
+    child.parent_link().is_some_and(|p| p.same_node(parent))
+}
+
+pub(crate) fn is_self_or_descendant_of<N: TreeLinks>(node: &N, other: &N) -> bool {
+    node.same_node(other) || Ancestors::of(node).any(|a| a.same_node(other))
+}
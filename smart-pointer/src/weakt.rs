@@ -1,38 +1,684 @@
-use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
 use std::rc::{Rc, Weak};
 
+use crate::dot::{DotGraph, Edge};
+use crate::refcellt_tracked::TrackedRefCell;
+use crate::tree_walk::{self, TreeLinks};
+
 #[derive(Debug)]
-struct Node {
-    value: i32,
-    parent: RefCell<Weak<Node>>,
-    children: RefCell<Vec<Rc<Node>>>,
+pub struct Node<T> {
+    value: T,
+    parent: TrackedRefCell<Weak<Node<T>>>,
+    children: TrackedRefCell<Vec<Rc<Node<T>>>>,
 }
 
+// Every child's `parent` points back at the node whose `children` holds it, and a
+// node is never its own ancestor. Children own their subtree through `Rc`, parents
+// are only reachable through `Weak`, so a tree never keeps itself alive.
+impl<T> Node<T> {
+    pub fn new(value: T) -> Rc<Node<T>> {
+        Rc::new(Node {
+            value,
+            parent: TrackedRefCell::new(Weak::new()),
+            children: TrackedRefCell::new(vec![]),
+        })
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn parent(&self) -> Option<Rc<Node<T>>> {
+        self.parent.borrow().upgrade()
+    }
+
+    /// A snapshot of the children; no borrow is held once it returns.
+    pub fn children(&self) -> Vec<Rc<Node<T>>> {
+        self.children.borrow().clone()
+    }
+
+    /// Appends `child` and points its parent at `self`, taking it away from its
+    /// previous parent first.
+    ///
+    /// Panics if `child` is `self` or one of its ancestors; see `try_add_child`.
+    pub fn add_child(self: &Rc<Self>, child: Rc<Node<T>>) {
+        if let Err(err) = self.try_add_child(child) {
+            panic!("{err}");
+        }
+    }
+
+    pub fn try_add_child(self: &Rc<Self>, child: Rc<Node<T>>) -> Result<(), TreeError> {
+        let len = self.children.borrow().len();
+        let index = if self.is_parent_of(&child) {
+            len - 1
+        } else {
+            len
+        };
+        self.insert_child(index, child)
+    }
+
+    /// Moves `child` with its whole subtree to position `index` among the children
+    /// of `self`. `index` counts the children as they are once `child` has left its
+    /// old place.
+    ///
+    /// Everything is checked before anything changes, so on error both parents and
+    /// `child` are left as they were.
+    pub fn insert_child(
+        self: &Rc<Self>,
+        index: usize,
+        child: Rc<Node<T>>,
+    ) -> Result<(), TreeError> {
+        if self.is_self_or_descendant_of(&child) {
+            return Err(TreeError::WouldCreateCycle);
+        }
+        let len = self.children.borrow().len() - usize::from(self.is_parent_of(&child));
+        if index > len {
+            return Err(TreeError::IndexOutOfBounds { index, len });
+        }
+
+        child.detach();
+        *child.parent.borrow_mut() = Rc::downgrade(self);
+        self.children.borrow_mut().insert(index, child);
+        Ok(())
+    }
+
+    /// Moves `self` with its subtree under `new_parent`, as its last child.
+    pub fn move_to(self: &Rc<Self>, new_parent: &Rc<Node<T>>) -> Result<(), TreeError> {
+        new_parent.try_add_child(Rc::clone(self))
+    }
+
+    /// Removes `child` from `self` and clears its parent. Returns `false` when
+    /// `child` isn't a child of `self`.
+    pub fn remove_child(&self, child: &Rc<Node<T>>) -> bool {
+        let removed = {
+            let mut children = self.children.borrow_mut();
+            children
+                .iter()
+                .position(|c| Rc::ptr_eq(c, child))
+                .map(|index| children.remove(index))
+        };
+
+        match removed {
+            Some(child) => {
+                *child.parent.borrow_mut() = Weak::new();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes `self` out of its parent, making it the root of its own tree.
+    pub fn detach(self: &Rc<Self>) {
+        if let Some(parent) = self.parent() {
+            parent.remove_child(self);
+        }
+    }
+
+    /// The parent, the grandparent and so on up to the root.
+    pub fn ancestors(self: &Rc<Self>) -> Ancestors<T> {
+        Ancestors::of(self)
+    }
+
+    /// Number of edges between `self` and its root.
+    pub fn depth(self: &Rc<Self>) -> usize {
+        self.ancestors().count()
+    }
+
+    pub fn root(self: &Rc<Self>) -> Rc<Node<T>> {
+        tree_walk::root(self)
+    }
+
+    /// The root first, `self` last.
+    pub fn path_from_root(self: &Rc<Self>) -> Vec<Rc<Node<T>>> {
+        tree_walk::path_from_root(self)
+    }
+
+    // The iterators keep no `RefCell` borrow between calls to `next`, so the tree
+    // can be changed while iterating; see `tree_walk`.
+
+    /// `self` first, then each child subtree from left to right.
+    pub fn preorder(self: &Rc<Self>) -> Preorder<T> {
+        Preorder::new(self)
+    }
+
+    /// Each child subtree from left to right, then `self`.
+    pub fn postorder(self: &Rc<Self>) -> Postorder<T> {
+        Postorder::new(self)
+    }
+
+    /// Level by level, starting at `self`.
+    pub fn breadth_first(self: &Rc<Self>) -> BreadthFirst<T> {
+        BreadthFirst::new(self)
+    }
+
+    /// The first node in preorder whose value matches `predicate`.
+    pub fn find<P>(self: &Rc<Self>, mut predicate: P) -> Option<Rc<Node<T>>>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        self.preorder().find(|node| predicate(&node.value))
+    }
+
+    /// Every node whose value matches `predicate`, in preorder.
+    pub fn find_all<P>(self: &Rc<Self>, mut predicate: P) -> Vec<Rc<Node<T>>>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        self.preorder()
+            .filter(|node| predicate(&node.value))
+            .collect()
+    }
+
+    pub fn leaves(self: &Rc<Self>) -> Vec<Rc<Node<T>>> {
+        tree_walk::leaves(self)
+    }
+
+    /// Number of edges on the longest path down to a leaf.
+    pub fn height(self: &Rc<Self>) -> usize {
+        tree_walk::height(self)
+    }
+
+    /// Number of nodes in the subtree, `self` included.
+    pub fn count(self: &Rc<Self>) -> usize {
+        self.preorder().count()
+    }
+
+    fn is_parent_of(self: &Rc<Self>, child: &Rc<Node<T>>) -> bool {
+        tree_walk::is_parent_of(self, child)
+    }
+
+    fn is_self_or_descendant_of(self: &Rc<Self>, other: &Rc<Node<T>>) -> bool {
+        tree_walk::is_self_or_descendant_of(self, other)
+    }
+}
+
+impl<T> TreeLinks for Rc<Node<T>> {
+    fn parent_link(&self) -> Option<Self> {
+        self.parent()
+    }
+
+    fn child_links(&self) -> Vec<Self> {
+        self.children()
+    }
+
+    fn same_node(&self, other: &Self) -> bool {
+        Rc::ptr_eq(self, other)
+    }
+}
+
+impl<T: fmt::Debug> Node<T> {
+    /// Graphviz DOT for the whole tree `self` belongs to: solid edges for the `Rc`
+    /// children, dashed ones for the `Weak` parent links.
+    pub fn to_dot(self: &Rc<Self>) -> String {
+        let mut graph = DotGraph::new();
+        let root = self.root();
+        graph.id(Rc::as_ptr(&root));
+        let mut queue = VecDeque::from([root]);
+        while let Some(node) = queue.pop_front() {
+            let (id, _) = graph.id(Rc::as_ptr(&node));
+            // Leave out the clone in `node`, which only exists while we walk.
+            graph.label(
+                id,
+                &format!("{:?}", node.value),
+                Rc::strong_count(&node) - 1,
+                Rc::weak_count(&node),
+            );
+
+            let parent = node.parent.borrow();
+            if parent.strong_count() > 0 {
+                let (parent_id, _) = graph.id(parent.as_ptr());
+                graph.edge(id, parent_id, Edge::Weak);
+            }
+            for child in node.children.borrow().iter() {
+                let (child_id, seen) = graph.id(Rc::as_ptr(child));
+                graph.edge(id, child_id, Edge::Strong);
+                if !seen {
+                    queue.push_back(Rc::clone(child));
+                }
+            }
+        }
+        graph.finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeError {
+    /// The new parent is the moved node itself or somewhere inside its subtree.
+    WouldCreateCycle,
+    IndexOutOfBounds {
+        index: usize,
+        len: usize,
+    },
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeError::WouldCreateCycle => {
+                write!(f, "adding this child would make a node its own ancestor")
+            }
+            TreeError::IndexOutOfBounds { index, len } => {
+                write!(f, "child index {index} is out of bounds for {len} children")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+pub type Ancestors<T> = tree_walk::Ancestors<Rc<Node<T>>>;
+pub type Preorder<T> = tree_walk::Preorder<Rc<Node<T>>>;
+pub type Postorder<T> = tree_walk::Postorder<Rc<Node<T>>>;
+pub type BreadthFirst<T> = tree_walk::BreadthFirst<Rc<Node<T>>>;
+
 #[cfg(test)]
 mod tests {
-    use super::Node;
-    use std::cell::RefCell;
+    use super::{Node, TreeError};
+    use crate::refcellt_tracked::TrackedRefCell;
+    use crate::test_util::LeakTracker;
     use std::rc::{Rc, Weak};
 
     #[test]
     fn run() {
-        let leaf = Rc::new(Node {
-            value: 3,
-            parent: RefCell::new(Weak::new()),
-            children: RefCell::new(vec![]),
-        });
-
-        let parent = leaf.parent.borrow().upgrade();
-        assert!(parent.is_none());
-
-        let branch = Rc::new(Node {
-            value: 5,
-            parent: RefCell::new(Weak::new()),
-            children: RefCell::new(vec![Rc::clone(&leaf)]),
-        });
-
-        *leaf.parent.borrow_mut() = Rc::downgrade(&branch);
-        let parent = leaf.parent.borrow().upgrade();
-        assert!(parent.is_some());
+        let tracker = LeakTracker::new();
+        {
+            let leaf = tracker.watch(
+                "leaf",
+                Rc::new(Node {
+                    value: 3,
+                    parent: TrackedRefCell::new(Weak::new()),
+                    children: TrackedRefCell::new(vec![]),
+                }),
+            );
+
+            let parent = leaf.parent.borrow().upgrade();
+            assert!(parent.is_none());
+
+            let branch = tracker.watch(
+                "branch",
+                Rc::new(Node {
+                    value: 5,
+                    parent: TrackedRefCell::new(Weak::new()),
+                    children: TrackedRefCell::new(vec![Rc::clone(&leaf)]),
+                }),
+            );
+
+            *leaf.parent.borrow_mut() = Rc::downgrade(&branch);
+            let parent = leaf.parent.borrow().upgrade();
+            assert!(parent.is_some());
+        }
+        // The child only holds a `Weak` to its parent, so nothing leaks.
+        tracker.assert_no_leaks();
+    }
+
+    // Checks the parent/children invariants for the whole tree under `root`.
+    fn assert_consistent<T>(root: &Rc<Node<T>>) {
+        let mut stack = vec![Rc::clone(root)];
+        while let Some(node) = stack.pop() {
+            for child in node.children() {
+                let parent = child.parent().expect("child without a parent");
+                assert!(
+                    Rc::ptr_eq(&parent, &node),
+                    "child points at the wrong parent"
+                );
+                stack.push(child);
+            }
+        }
+    }
+
+    fn values<T: Copy>(nodes: &[Rc<Node<T>>]) -> Vec<T> {
+        nodes.iter().map(|n| *n.value()).collect()
+    }
+
+    #[test]
+    fn add_child_wires_the_parent() {
+        let branch = Node::new(5);
+        let leaf = Node::new(3);
+        branch.add_child(Rc::clone(&leaf));
+
+        assert!(Rc::ptr_eq(&leaf.parent().unwrap(), &branch));
+        assert_eq!(values(&branch.children()), vec![3]);
+        assert_eq!(Rc::strong_count(&leaf), 2);
+        assert_eq!(Rc::strong_count(&branch), 1);
+        assert_eq!(Rc::weak_count(&branch), 1);
+        assert_consistent(&branch);
+    }
+
+    #[test]
+    fn remove_child_and_detach() {
+        let root = Node::new(1);
+        let a = Node::new(2);
+        let b = Node::new(3);
+        root.add_child(Rc::clone(&a));
+        root.add_child(Rc::clone(&b));
+
+        assert!(root.remove_child(&a));
+        assert!(!root.remove_child(&a));
+        assert!(a.parent().is_none());
+        assert_eq!(values(&root.children()), vec![3]);
+
+        b.detach();
+        assert!(b.parent().is_none());
+        assert!(root.children().is_empty());
+        assert_eq!(Rc::weak_count(&root), 0);
+
+        // Detaching a root does nothing.
+        root.detach();
+        assert_consistent(&root);
+    }
+
+    #[test]
+    fn add_child_moves_from_the_previous_parent() {
+        let old = Node::new(1);
+        let new = Node::new(2);
+        let child = Node::new(3);
+        old.add_child(Rc::clone(&child));
+        new.add_child(Rc::clone(&child));
+
+        assert!(old.children().is_empty());
+        assert!(Rc::ptr_eq(&child.parent().unwrap(), &new));
+        assert_eq!(Rc::strong_count(&child), 2);
+        assert_consistent(&old);
+        assert_consistent(&new);
+    }
+
+    #[test]
+    #[should_panic(expected = "its own ancestor")]
+    fn add_child_rejects_an_ancestor() {
+        let root = Node::new(1);
+        let child = Node::new(2);
+        root.add_child(Rc::clone(&child));
+        child.add_child(root);
+    }
+
+    #[test]
+    fn ancestors_depth_root_and_path() {
+        let root = Node::new(1);
+        let middle = Node::new(2);
+        let leaf = Node::new(3);
+        root.add_child(Rc::clone(&middle));
+        middle.add_child(Rc::clone(&leaf));
+
+        assert_eq!(values(&leaf.ancestors().collect::<Vec<_>>()), vec![2, 1]);
+        assert_eq!((root.depth(), middle.depth(), leaf.depth()), (0, 1, 2));
+        assert!(Rc::ptr_eq(&leaf.root(), &root));
+        assert!(Rc::ptr_eq(&root.root(), &root));
+        assert_eq!(values(&leaf.path_from_root()), vec![1, 2, 3]);
+        assert_eq!(values(&root.path_from_root()), vec![1]);
+    }
+
+    #[test]
+    fn dropping_the_root_frees_the_tree() {
+        let tracker = LeakTracker::new();
+        let leaf = tracker.watch("leaf", Node::new(3));
+        {
+            let root = tracker.watch("root", Node::new(1));
+            let middle = tracker.watch("middle", Node::new(2));
+            root.add_child(Rc::clone(&middle));
+            middle.add_child(Rc::clone(&leaf));
+            assert_eq!(leaf.depth(), 2);
+        }
+
+        // Only our own handle keeps `leaf` alive, and its parent link is dangling.
+        assert!(leaf.parent().is_none());
+        assert_eq!(leaf.depth(), 0);
+        drop(leaf);
+        tracker.assert_no_leaks();
+    }
+
+    //        1
+    //      / | \
+    //     2  3  4
+    //    / \     \
+    //   5   6     7
+    //             |
+    //             8
+    fn sample_tree() -> Rc<Node<i32>> {
+        let node = |value, children: Vec<Rc<Node<i32>>>| {
+            let node = Node::new(value);
+            for child in children {
+                node.add_child(child);
+            }
+            node
+        };
+        node(
+            1,
+            vec![
+                node(2, vec![node(5, vec![]), node(6, vec![])]),
+                node(3, vec![]),
+                node(4, vec![node(7, vec![node(8, vec![])])]),
+            ],
+        )
+    }
+
+    #[test]
+    fn traversal_orders() {
+        let root = sample_tree();
+        assert_eq!(
+            values(&root.preorder().collect::<Vec<_>>()),
+            vec![1, 2, 5, 6, 3, 4, 7, 8]
+        );
+        assert_eq!(
+            values(&root.postorder().collect::<Vec<_>>()),
+            vec![5, 6, 2, 3, 8, 7, 4, 1]
+        );
+        assert_eq!(
+            values(&root.breadth_first().collect::<Vec<_>>()),
+            vec![1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        let leaf = Node::new(9);
+        assert_eq!(values(&leaf.postorder().collect::<Vec<_>>()), vec![9]);
+    }
+
+    #[test]
+    fn queries() {
+        let root = sample_tree();
+        assert_eq!(root.find(|v| v % 2 == 0).map(|n| *n.value()), Some(2));
+        assert!(root.find(|v| *v > 100).is_none());
+        assert_eq!(values(&root.find_all(|v| v % 2 == 0)), vec![2, 6, 4, 8]);
+        assert_eq!(values(&root.leaves()), vec![5, 6, 3, 8]);
+        assert_eq!(root.height(), 3);
+        assert_eq!(root.count(), 8);
+
+        let four = root.find(|v| *v == 4).unwrap();
+        assert_eq!((four.height(), four.count()), (2, 3));
+        assert_eq!(Node::new(0).height(), 0);
+    }
+
+    #[test]
+    fn tree_can_change_while_iterating() {
+        let root = sample_tree();
+        let spare = Node::new(10);
+
+        let mut visited = vec![];
+        for node in root.preorder() {
+            // Would panic with a borrow conflict if the iterator held a borrow.
+            if *node.value() == 2 {
+                node.add_child(Node::new(20));
+                root.add_child(Rc::clone(&spare));
+            }
+            if *node.value() == 3 {
+                node.detach();
+            }
+            visited.push(*node.value());
+        }
+
+        // `2` and the root were already expanded, so `20` and `10` aren't visited.
+        assert_eq!(visited, vec![1, 2, 5, 6, 3, 4, 7, 8]);
+        assert_eq!(values(&root.children()), vec![2, 4, 10]);
+        assert_eq!(root.count(), 9);
+        assert_consistent(&root);
+
+        for node in root.breadth_first() {
+            if node.children().is_empty() {
+                node.add_child(Node::new(0));
+            }
+            if *node.value() == 0 {
+                break;
+            }
+        }
+        assert_consistent(&root);
+    }
+
+    // (strong, weak) for each node.
+    fn counts<T>(nodes: &[&Rc<Node<T>>]) -> Vec<(usize, usize)> {
+        nodes
+            .iter()
+            .map(|n| (Rc::strong_count(n), Rc::weak_count(n)))
+            .collect()
+    }
+
+    #[test]
+    fn move_subtree_between_parents() {
+        let tracker = LeakTracker::new();
+        {
+            let root = tracker.watch("root", Node::new(1));
+            let left = tracker.watch("left", Node::new(2));
+            let right = tracker.watch("right", Node::new(3));
+            let moved = tracker.watch("moved", Node::new(4));
+            let grandchild = tracker.watch("grandchild", Node::new(5));
+            root.add_child(Rc::clone(&left));
+            root.add_child(Rc::clone(&right));
+            left.add_child(Rc::clone(&moved));
+            moved.add_child(Rc::clone(&grandchild));
+
+            // Strong: our handle plus the parent's `children`.
+            // Weak: one per child, plus the one held by the tracker.
+            assert_eq!(
+                counts(&[&root, &left, &right, &moved, &grandchild]),
+                vec![(1, 3), (2, 2), (2, 1), (2, 2), (2, 1)]
+            );
+
+            moved.move_to(&right).unwrap();
+            assert!(left.children().is_empty());
+            assert_eq!(values(&right.children()), vec![4]);
+            assert!(Rc::ptr_eq(&moved.parent().unwrap(), &right));
+            assert!(Rc::ptr_eq(&grandchild.root(), &root));
+            assert_eq!(
+                counts(&[&root, &left, &right, &moved, &grandchild]),
+                vec![(1, 3), (2, 1), (2, 2), (2, 2), (2, 1)]
+            );
+            assert_consistent(&root);
+
+            // Up to the root, next to the old parent.
+            moved.move_to(&root).unwrap();
+            assert_eq!(values(&root.children()), vec![2, 3, 4]);
+            assert_eq!(
+                counts(&[&root, &left, &right, &moved, &grandchild]),
+                vec![(1, 4), (2, 1), (2, 1), (2, 2), (2, 1)]
+            );
+            assert_consistent(&root);
+        }
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn moves_that_would_create_a_cycle_are_rejected() {
+        let root = Node::new(1);
+        let child = Node::new(2);
+        let grandchild = Node::new(3);
+        root.add_child(Rc::clone(&child));
+        child.add_child(Rc::clone(&grandchild));
+        let before = counts(&[&root, &child, &grandchild]);
+
+        assert_eq!(root.move_to(&grandchild), Err(TreeError::WouldCreateCycle));
+        assert_eq!(child.move_to(&child), Err(TreeError::WouldCreateCycle));
+        assert_eq!(
+            grandchild.try_add_child(Rc::clone(&root)),
+            Err(TreeError::WouldCreateCycle)
+        );
+
+        // Nothing changed.
+        assert!(root.parent().is_none());
+        assert_eq!(values(&grandchild.path_from_root()), vec![1, 2, 3]);
+        assert_eq!(counts(&[&root, &child, &grandchild]), before);
+        assert_consistent(&root);
+    }
+
+    #[test]
+    fn insert_child_positions_and_bounds() {
+        let root = Node::new(0);
+        let nodes: Vec<_> = (1..=3).map(Node::new).collect();
+        for node in &nodes {
+            root.add_child(Rc::clone(node));
+        }
+
+        // Reorder within the same parent: 3 to the front.
+        root.insert_child(0, Rc::clone(&nodes[2])).unwrap();
+        assert_eq!(values(&root.children()), vec![3, 1, 2]);
+        assert_eq!(Rc::strong_count(&nodes[2]), 2);
+
+        // Re-adding an existing child moves it to the end.
+        root.try_add_child(Rc::clone(&nodes[2])).unwrap();
+        assert_eq!(values(&root.children()), vec![1, 2, 3]);
+
+        assert_eq!(
+            root.insert_child(3, Rc::clone(&nodes[0])),
+            Err(TreeError::IndexOutOfBounds { index: 3, len: 2 })
+        );
+        let other = Node::new(9);
+        assert_eq!(
+            other.insert_child(1, Rc::clone(&nodes[0])),
+            Err(TreeError::IndexOutOfBounds { index: 1, len: 0 })
+        );
+        assert_eq!(values(&root.children()), vec![1, 2, 3]);
+        assert_consistent(&root);
+    }
+
+    #[test]
+    fn to_dot_draws_parents_as_weak_edges() {
+        let root = Node::new(5);
+        let left = Node::new(3);
+        let right = Node::new(7);
+        root.add_child(Rc::clone(&left));
+        root.add_child(Rc::clone(&right));
+        right.add_child(Node::new(8));
+
+        // Any node gives the whole tree, and the parent links never form a cycle.
+        let dot = right.to_dot();
+        assert_eq!(
+            dot,
+            concat!(
+                "digraph {\n",
+                "    node [shape=box];\n",
+                "    n0 [label=\"5\\nstrong: 1, weak: 2\"];\n",
+                "    n1 [label=\"3\\nstrong: 2, weak: 0\"];\n",
+                "    n2 [label=\"7\\nstrong: 2, weak: 1\"];\n",
+                "    n3 [label=\"8\\nstrong: 1, weak: 0\"];\n",
+                "    n0 -> n1;\n",
+                "    n0 -> n2;\n",
+                "    n1 -> n0 [style=dashed];\n",
+                "    n2 -> n0 [style=dashed];\n",
+                "    n2 -> n3;\n",
+                "    n3 -> n2 [style=dashed];\n",
+                "}\n",
+            )
+        );
+        assert_eq!(root.to_dot(), dot);
+    }
+
+    #[cfg(any(debug_assertions, feature = "track-borrows"))]
+    #[test]
+    fn borrow_conflict_names_the_holder() {
+        let root = Node::new(1);
+        let held_line = line!() + 1;
+        let children = root.children.borrow();
+
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            root.add_child(Node::new(2));
+        }))
+        .unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(
+            message.contains(&format!(
+                "conflicts with borrow at {}:{held_line}:",
+                file!()
+            )),
+            "{message}"
+        );
+        drop(children);
+        root.add_child(Node::new(2));
     }
 }
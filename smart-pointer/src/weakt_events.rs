@@ -0,0 +1,470 @@
+//! DOM-style events over `weakt::Node` trees.
+//!
+//! `Node` itself knows nothing about events. A `Listeners` table keeps the
+//! handlers on the side, keyed by node, so trees that never listen to anything pay
+//! nothing for it.
+//!
+//! `dispatch` walks the `Weak` parent links once to find the path from the root to
+//! the target. Capture listeners run from the root down, then the target's own
+//! listeners, then bubble listeners from the target's parent back up to the root.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use crate::weakt::Node;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Capturing,
+    AtTarget,
+    Bubbling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerId(usize);
+
+pub struct Event<'a, T> {
+    kind: String,
+    phase: Phase,
+    target: Rc<Node<T>>,
+    current_target: Rc<Node<T>>,
+    listeners: &'a Listeners<T>,
+    propagation_stopped: bool,
+    immediate_propagation_stopped: bool,
+    default_prevented: bool,
+}
+
+impl<'a, T> Event<'a, T> {
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// The node `dispatch` was called on.
+    pub fn target(&self) -> &Rc<Node<T>> {
+        &self.target
+    }
+
+    /// The node whose listener is running right now.
+    pub fn current_target(&self) -> &Rc<Node<T>> {
+        &self.current_target
+    }
+
+    /// The table dispatching this event, for handlers that add or remove listeners.
+    pub fn listeners(&self) -> &'a Listeners<T> {
+        self.listeners
+    }
+
+    /// Lets the remaining listeners on the current node run, then stops.
+    pub fn stop_propagation(&mut self) {
+        self.propagation_stopped = true;
+    }
+
+    /// Stops before any other listener runs, even on the current node.
+    pub fn stop_immediate_propagation(&mut self) {
+        self.propagation_stopped = true;
+        self.immediate_propagation_stopped = true;
+    }
+
+    pub fn prevent_default(&mut self) {
+        self.default_prevented = true;
+    }
+
+    pub fn default_prevented(&self) -> bool {
+        self.default_prevented
+    }
+}
+
+type Handler<T> = Rc<dyn Fn(&mut Event<'_, T>)>;
+
+struct Listener<T> {
+    id: ListenerId,
+    kind: String,
+    capture: bool,
+    handler: Handler<T>,
+}
+
+// The `Weak` keeps the node's allocation, and so its address, from being reused
+// while the entry exists; once the node is gone the entry is pruned.
+struct Entry<T> {
+    node: Weak<Node<T>>,
+    // Kept behind `Rc` so a dispatch can snapshot the list and call the handlers with
+    // no borrow held; a handler can then add or remove listeners, or reshape the tree.
+    listeners: Vec<Rc<Listener<T>>>,
+}
+
+/// Event handlers for the nodes of any number of trees.
+///
+/// The table owns the handlers, not the nodes. Handlers registered on a node
+/// that has since been dropped are dropped with the table, or by the next
+/// `add_listener`.
+pub struct Listeners<T> {
+    next_id: Cell<usize>,
+    entries: RefCell<HashMap<*const Node<T>, Entry<T>>>,
+}
+
+impl<T> Listeners<T> {
+    pub fn new() -> Listeners<T> {
+        Listeners {
+            next_id: Cell::new(0),
+            entries: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Registers `handler` on `node` for events of `kind`. A capture listener runs
+    /// on the way down from the root, any other listener on the way back up.
+    ///
+    /// A handler holding an `Rc` to the table is a cycle and leaks along with
+    /// everything it holds; reach the table through `Event::listeners` instead.
+    pub fn add_listener<F>(
+        &self,
+        node: &Rc<Node<T>>,
+        kind: &str,
+        capture: bool,
+        handler: F,
+    ) -> ListenerId
+    where
+        F: Fn(&mut Event<'_, T>) + 'static,
+    {
+        let id = ListenerId(self.next_id.get());
+        self.next_id.set(id.0 + 1);
+
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|_, entry| entry.node.strong_count() > 0);
+        let entry = entries.entry(Rc::as_ptr(node)).or_insert_with(|| Entry {
+            node: Rc::downgrade(node),
+            listeners: vec![],
+        });
+        entry.listeners.push(Rc::new(Listener {
+            id,
+            kind: kind.to_string(),
+            capture,
+            handler: Rc::new(handler),
+        }));
+        id
+    }
+
+    pub fn remove_listener(&self, id: ListenerId) -> bool {
+        let mut entries = self.entries.borrow_mut();
+        for entry in entries.values_mut() {
+            let len = entry.listeners.len();
+            entry.listeners.retain(|listener| listener.id != id);
+            if entry.listeners.len() != len {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Sends an event of `kind` to `target`. Returns `false` if a listener called
+    /// `prevent_default`.
+    ///
+    /// The path is fixed when dispatch starts, so moving nodes from inside a
+    /// handler doesn't change who receives this event.
+    pub fn dispatch(&self, target: &Rc<Node<T>>, kind: &str) -> bool {
+        let path = target.path_from_root();
+        let (target, ancestors) = path.split_last().expect("path includes the target");
+        let mut event = Event {
+            kind: kind.to_string(),
+            phase: Phase::Capturing,
+            target: Rc::clone(target),
+            current_target: Rc::clone(target),
+            listeners: self,
+            propagation_stopped: false,
+            immediate_propagation_stopped: false,
+            default_prevented: false,
+        };
+
+        let steps = ancestors
+            .iter()
+            .map(|node| (node, Phase::Capturing, Some(true)))
+            .chain([(target, Phase::AtTarget, None)])
+            .chain(
+                ancestors
+                    .iter()
+                    .rev()
+                    .map(|node| (node, Phase::Bubbling, Some(false))),
+            );
+
+        for (node, phase, capture) in steps {
+            event.phase = phase;
+            event.current_target = Rc::clone(node);
+            self.notify(node, &mut event, capture);
+            if event.propagation_stopped {
+                break;
+            }
+        }
+        !event.default_prevented
+    }
+
+    // `capture` picks which listeners run; `None` runs capture listeners and then
+    // the others, as happens at the target.
+    fn notify(&self, node: &Rc<Node<T>>, event: &mut Event<'_, T>, capture: Option<bool>) {
+        let snapshot: Vec<_> = match self.entries.borrow().get(&Rc::as_ptr(node)) {
+            Some(entry) => entry
+                .listeners
+                .iter()
+                .filter(|listener| listener.kind == event.kind)
+                .cloned()
+                .collect(),
+            None => return,
+        };
+
+        let order = match capture {
+            Some(capture) => vec![capture],
+            None => vec![true, false],
+        };
+        for capture in order {
+            for listener in snapshot.iter().filter(|l| l.capture == capture) {
+                (listener.handler)(event);
+                if event.immediate_propagation_stopped {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Default for Listeners<T> {
+    fn default() -> Listeners<T> {
+        Listeners::new()
+    }
+}
+
+impl<T> fmt::Debug for Listeners<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.entries.borrow();
+        let len: usize = entries.values().map(|entry| entry.listeners.len()).sum();
+        write!(f, "Listeners({len})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Listeners, Phase};
+    use crate::test_util::LeakTracker;
+    use crate::weakt::Node;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+    type TestNode = Rc<Node<&'static str>>;
+
+    // Registers a capture and a bubble listener on `node` that record what they saw.
+    fn listen(listeners: &Listeners<&'static str>, node: &TestNode, log: &Log) {
+        for capture in [true, false] {
+            let log = Rc::clone(log);
+            listeners.add_listener(node, "click", capture, move |event: &mut Event<&str>| {
+                log.borrow_mut().push(format!(
+                    "{}:{:?}",
+                    event.current_target().value(),
+                    event.phase()
+                ));
+            });
+        }
+    }
+
+    // root -> panel -> button
+    fn sample_tree() -> (TestNode, TestNode, TestNode) {
+        let root = Node::new("root");
+        let panel = Node::new("panel");
+        let button = Node::new("button");
+        root.add_child(Rc::clone(&panel));
+        panel.add_child(Rc::clone(&button));
+        (root, panel, button)
+    }
+
+    #[test]
+    fn capture_target_and_bubble_order() {
+        let (root, panel, button) = sample_tree();
+        let listeners = Listeners::new();
+        let log = Log::default();
+        for node in [&root, &panel, &button] {
+            listen(&listeners, node, &log);
+        }
+
+        assert!(listeners.dispatch(&button, "click"));
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "root:Capturing",
+                "panel:Capturing",
+                "button:AtTarget",
+                "button:AtTarget",
+                "panel:Bubbling",
+                "root:Bubbling",
+            ]
+        );
+
+        // Other kinds don't reach these listeners.
+        log.borrow_mut().clear();
+        assert!(listeners.dispatch(&button, "keydown"));
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn stop_propagation_finishes_the_current_node() {
+        let (root, panel, button) = sample_tree();
+        let listeners = Listeners::new();
+        let log = Log::default();
+        listen(&listeners, &root, &log);
+        listeners.add_listener(&panel, "click", true, |event: &mut Event<&str>| {
+            event.stop_propagation();
+        });
+        listen(&listeners, &panel, &log);
+        listen(&listeners, &button, &log);
+
+        listeners.dispatch(&button, "click");
+        assert_eq!(*log.borrow(), vec!["root:Capturing", "panel:Capturing"]);
+    }
+
+    #[test]
+    fn stop_immediate_propagation_skips_the_rest() {
+        let (root, _panel, button) = sample_tree();
+        let listeners = Listeners::new();
+        let log = Log::default();
+        listen(&listeners, &root, &log);
+        listeners.add_listener(&button, "click", false, |event: &mut Event<&str>| {
+            event.stop_immediate_propagation();
+        });
+        listen(&listeners, &button, &log);
+
+        // The button's own capture listener still runs before the one that stops.
+        listeners.dispatch(&button, "click");
+        assert_eq!(*log.borrow(), vec!["root:Capturing", "button:AtTarget"]);
+    }
+
+    #[test]
+    fn prevent_default_is_reported() {
+        let (root, _panel, button) = sample_tree();
+        let listeners = Listeners::new();
+        listeners.add_listener(&root, "submit", false, |event: &mut Event<&str>| {
+            assert_eq!(*event.target().value(), "button");
+            event.prevent_default();
+        });
+
+        assert!(!listeners.dispatch(&button, "submit"));
+        assert!(listeners.dispatch(&button, "click"));
+    }
+
+    #[test]
+    fn remove_listener() {
+        let (_root, _panel, button) = sample_tree();
+        let listeners = Listeners::new();
+        let log = Log::default();
+        let id = {
+            let log = Rc::clone(&log);
+            listeners.add_listener(&button, "click", false, move |_: &mut Event<&str>| {
+                log.borrow_mut().push(String::from("called"));
+            })
+        };
+
+        assert!(listeners.remove_listener(id));
+        assert!(!listeners.remove_listener(id));
+        listeners.dispatch(&button, "click");
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn handlers_can_change_the_tree_during_dispatch() {
+        let (root, panel, button) = sample_tree();
+        let listeners = Listeners::new();
+        let log = Log::default();
+        listen(&listeners, &root, &log);
+        listen(&listeners, &button, &log);
+
+        // Moves the button out of the panel and adds listeners mid-dispatch.
+        let moved_id = {
+            let root = Rc::clone(&root);
+            let log = Rc::clone(&log);
+            listeners.add_listener(&panel, "click", true, move |event: &mut Event<&str>| {
+                event.target().move_to(&root).unwrap();
+                event.current_target().add_child(Node::new("new"));
+                let log = Rc::clone(&log);
+                event.listeners().add_listener(
+                    event.current_target(),
+                    "click",
+                    false,
+                    move |_: &mut Event<&str>| {
+                        log.borrow_mut().push(String::from("late"));
+                    },
+                );
+            })
+        };
+        // A bubble listener that removes the capture one.
+        listeners.add_listener(&panel, "click", false, move |event: &mut Event<&str>| {
+            event.listeners().remove_listener(moved_id);
+        });
+
+        listeners.dispatch(&button, "click");
+        // The path was fixed up front, so the panel still gets the bubble phase,
+        // including the listener added during capture.
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "root:Capturing",
+                "button:AtTarget",
+                "button:AtTarget",
+                "late",
+                "root:Bubbling",
+            ]
+        );
+        assert!(Rc::ptr_eq(&button.parent().unwrap(), &root));
+        assert_eq!(panel.children().len(), 1);
+
+        // Next time the button is a direct child of the root, and the panel's
+        // capture listener is gone.
+        log.borrow_mut().clear();
+        listeners.dispatch(&button, "click");
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "root:Capturing",
+                "button:AtTarget",
+                "button:AtTarget",
+                "root:Bubbling",
+            ]
+        );
+        listeners.dispatch(&panel, "click");
+        assert!(log.borrow().contains(&String::from("late")));
+    }
+
+    #[test]
+    fn handlers_may_hold_their_nodes() {
+        let tracker = LeakTracker::new();
+        let listeners = Listeners::new();
+        {
+            let root = tracker.watch("root", Node::new("root"));
+            let child = tracker.watch("child", Node::new("child"));
+            root.add_child(Rc::clone(&child));
+
+            // The table owns this handler, not the node, so this is no cycle.
+            let held = Rc::clone(&root);
+            listeners.add_listener(&child, "click", false, move |event: &mut Event<&str>| {
+                assert_eq!(*held.value(), "root");
+                assert_eq!(event.phase(), Phase::AtTarget);
+            });
+            assert!(listeners.dispatch(&child, "click"));
+        }
+        assert_eq!(format!("{listeners:?}"), "Listeners(1)");
+        drop(listeners);
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn entries_for_dropped_nodes_are_pruned() {
+        let listeners = Listeners::new();
+        let gone = Node::new("gone");
+        listeners.add_listener(&gone, "click", false, |_: &mut Event<&str>| {});
+        drop(gone);
+
+        let kept = Node::new("kept");
+        listeners.add_listener(&kept, "click", false, |_: &mut Event<&str>| {});
+        assert_eq!(format!("{listeners:?}"), "Listeners(1)");
+    }
+}
@@ -0,0 +1,458 @@
+//! The `weakt::Node` tree with `Arc`, `sync::Weak` and `RwLock` in place of `Rc`,
+//! `Weak` and `RefCell`, so a tree can be shared with other threads.
+
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use crate::tree_walk::{self, TreeLinks};
+use crate::weakt::TreeError;
+
+#[derive(Debug)]
+pub struct SyncNode<T> {
+    value: T,
+    parent: RwLock<Weak<SyncNode<T>>>,
+    children: RwLock<Vec<Arc<SyncNode<T>>>>,
+    // Only used while this node is a root: it guards the shape of its whole tree.
+    structure: Mutex<()>,
+}
+
+impl<T> SyncNode<T> {
+    pub fn new(value: T) -> Arc<SyncNode<T>> {
+        Arc::new(SyncNode {
+            value,
+            parent: RwLock::new(Weak::new()),
+            children: RwLock::new(vec![]),
+            structure: Mutex::new(()),
+        })
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn parent(&self) -> Option<Arc<SyncNode<T>>> {
+        self.parent.read().unwrap().upgrade()
+    }
+
+    /// A snapshot of the children; no lock is held once it returns.
+    pub fn children(&self) -> Vec<Arc<SyncNode<T>>> {
+        self.children.read().unwrap().clone()
+    }
+
+    /// Panics if `child` is `self` or one of its ancestors; see `try_add_child`.
+    pub fn add_child(self: &Arc<Self>, child: Arc<SyncNode<T>>) {
+        if let Err(err) = self.try_add_child(child) {
+            panic!("{err}");
+        }
+    }
+
+    pub fn try_add_child(self: &Arc<Self>, child: Arc<SyncNode<T>>) -> Result<(), TreeError> {
+        with_trees(&[self, &child], || {
+            let len = self.children.read().unwrap().len();
+            let index = if self.is_parent_of(&child) {
+                len - 1
+            } else {
+                len
+            };
+            self.insert_locked(index, child.clone())
+        })
+    }
+
+    /// Like `weakt::Node::insert_child`: `index` counts the children as they are
+    /// once `child` has left its old place, and nothing changes on error.
+    pub fn insert_child(
+        self: &Arc<Self>,
+        index: usize,
+        child: Arc<SyncNode<T>>,
+    ) -> Result<(), TreeError> {
+        with_trees(&[self, &child], || self.insert_locked(index, child.clone()))
+    }
+
+    pub fn move_to(self: &Arc<Self>, new_parent: &Arc<SyncNode<T>>) -> Result<(), TreeError> {
+        new_parent.try_add_child(Arc::clone(self))
+    }
+
+    /// Removes `child` from `self` and clears its parent. Returns `false` when
+    /// `child` isn't a child of `self`.
+    pub fn remove_child(&self, child: &Arc<SyncNode<T>>) -> bool {
+        // A child shares its parent's tree, so locking the child's tree is enough.
+        // If `child` isn't ours, it can't become ours while its tree is locked.
+        with_trees(&[child], || self.remove_locked(child))
+    }
+
+    pub fn detach(self: &Arc<Self>) {
+        with_trees(&[self], || {
+            if let Some(parent) = self.parent() {
+                parent.remove_locked(self);
+            }
+        });
+    }
+
+    pub fn ancestors(self: &Arc<Self>) -> Ancestors<T> {
+        Ancestors::of(self)
+    }
+
+    pub fn depth(self: &Arc<Self>) -> usize {
+        self.ancestors().count()
+    }
+
+    pub fn root(self: &Arc<Self>) -> Arc<SyncNode<T>> {
+        tree_walk::root(self)
+    }
+
+    pub fn path_from_root(self: &Arc<Self>) -> Vec<Arc<SyncNode<T>>> {
+        tree_walk::path_from_root(self)
+    }
+
+    // As with `weakt::Node`, the iterators hold no lock between calls to `next`.
+
+    pub fn preorder(self: &Arc<Self>) -> Preorder<T> {
+        Preorder::new(self)
+    }
+
+    pub fn postorder(self: &Arc<Self>) -> Postorder<T> {
+        Postorder::new(self)
+    }
+
+    pub fn breadth_first(self: &Arc<Self>) -> BreadthFirst<T> {
+        BreadthFirst::new(self)
+    }
+
+    pub fn find<P>(self: &Arc<Self>, mut predicate: P) -> Option<Arc<SyncNode<T>>>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        self.preorder().find(|node| predicate(&node.value))
+    }
+
+    pub fn find_all<P>(self: &Arc<Self>, mut predicate: P) -> Vec<Arc<SyncNode<T>>>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        self.preorder()
+            .filter(|node| predicate(&node.value))
+            .collect()
+    }
+
+    pub fn leaves(self: &Arc<Self>) -> Vec<Arc<SyncNode<T>>> {
+        tree_walk::leaves(self)
+    }
+
+    pub fn height(self: &Arc<Self>) -> usize {
+        tree_walk::height(self)
+    }
+
+    pub fn count(self: &Arc<Self>) -> usize {
+        self.preorder().count()
+    }
+
+    // Callers hold the `structure` lock of both trees.
+    fn insert_locked(
+        self: &Arc<Self>,
+        index: usize,
+        child: Arc<SyncNode<T>>,
+    ) -> Result<(), TreeError> {
+        if self.is_self_or_descendant_of(&child) {
+            return Err(TreeError::WouldCreateCycle);
+        }
+        let old_parent = child.parent();
+        let same_parent = old_parent.as_ref().is_some_and(|p| Arc::ptr_eq(p, self));
+        let len = self.children.read().unwrap().len() - usize::from(same_parent);
+        if index > len {
+            return Err(TreeError::IndexOutOfBounds { index, len });
+        }
+
+        // Hold the child's and the new parent's locks for the whole update, so no
+        // single node is ever seen half-changed. Readers don't take the structure
+        // lock, though, and a walk that runs during the move can meet some nodes
+        // before it and some after.
+        let mut child_parent = child.parent.write().unwrap();
+        let mut children = self.children.write().unwrap();
+        match &old_parent {
+            Some(old) if !same_parent => {
+                old.children
+                    .write()
+                    .unwrap()
+                    .retain(|c| !Arc::ptr_eq(c, &child));
+            }
+            Some(_) => children.retain(|c| !Arc::ptr_eq(c, &child)),
+            None => {}
+        }
+        *child_parent = Arc::downgrade(self);
+        children.insert(index, child.clone());
+        Ok(())
+    }
+
+    // Callers hold the `structure` lock of the tree.
+    fn remove_locked(&self, child: &Arc<SyncNode<T>>) -> bool {
+        let mut child_parent = child.parent.write().unwrap();
+        let mut children = self.children.write().unwrap();
+        match children.iter().position(|c| Arc::ptr_eq(c, child)) {
+            Some(index) => {
+                children.remove(index);
+                *child_parent = Weak::new();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_parent_of(self: &Arc<Self>, child: &Arc<SyncNode<T>>) -> bool {
+        tree_walk::is_parent_of(self, child)
+    }
+
+    fn is_self_or_descendant_of(self: &Arc<Self>, other: &Arc<SyncNode<T>>) -> bool {
+        tree_walk::is_self_or_descendant_of(self, other)
+    }
+}
+
+// Runs `f` holding the `structure` lock of every tree that `nodes` belong to.
+//
+// Changing a tree's shape needs its root's lock: moving a node changes only its
+// own tree and the one it joins, and a root only gets a parent by being moved. So
+// once the locks are held and each node still reaches a locked root, none of those
+// roots can change until `f` returns. A node whose root changed while we waited
+// sends us around again. Roots are locked in address order, so two moves between
+// the same trees can't deadlock, and moves within other trees don't wait at all.
+fn with_trees<T, R>(nodes: &[&Arc<SyncNode<T>>], f: impl FnOnce() -> R) -> R {
+    loop {
+        let mut roots: Vec<_> = nodes.iter().map(|node| node.root()).collect();
+        roots.sort_by_key(Arc::as_ptr);
+        roots.dedup_by(|a, b| Arc::ptr_eq(a, b));
+        let _guards: Vec<_> = roots
+            .iter()
+            .map(|root| root.structure.lock().unwrap())
+            .collect();
+        let settled = nodes
+            .iter()
+            .all(|node| roots.iter().any(|root| Arc::ptr_eq(&node.root(), root)));
+        if settled {
+            return f();
+        }
+    }
+}
+
+impl<T> TreeLinks for Arc<SyncNode<T>> {
+    fn parent_link(&self) -> Option<Self> {
+        self.parent()
+    }
+
+    fn child_links(&self) -> Vec<Self> {
+        self.children()
+    }
+
+    fn same_node(&self, other: &Self) -> bool {
+        Arc::ptr_eq(self, other)
+    }
+}
+
+pub type Ancestors<T> = tree_walk::Ancestors<Arc<SyncNode<T>>>;
+pub type Preorder<T> = tree_walk::Preorder<Arc<SyncNode<T>>>;
+pub type Postorder<T> = tree_walk::Postorder<Arc<SyncNode<T>>>;
+pub type BreadthFirst<T> = tree_walk::BreadthFirst<Arc<SyncNode<T>>>;
+
+#[cfg(test)]
+mod tests {
+    use super::SyncNode;
+    use crate::weakt::TreeError;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn values<T: Copy>(nodes: &[Arc<SyncNode<T>>]) -> Vec<T> {
+        nodes.iter().map(|n| *n.value()).collect()
+    }
+
+    // Every child points back at its parent, exactly once, and every node reaches
+    // the root without looping.
+    fn assert_consistent<T>(root: &Arc<SyncNode<T>>, expected_count: usize) {
+        let nodes: Vec<_> = root.preorder().take(expected_count + 1).collect();
+        assert_eq!(nodes.len(), expected_count);
+        for node in &nodes {
+            for child in node.children() {
+                assert!(Arc::ptr_eq(&child.parent().unwrap(), node));
+                let copies = node
+                    .children()
+                    .iter()
+                    .filter(|c| Arc::ptr_eq(c, &child))
+                    .count();
+                assert_eq!(copies, 1);
+            }
+            assert!(node.depth() < expected_count);
+            assert!(Arc::ptr_eq(&node.root(), root));
+        }
+    }
+
+    #[test]
+    fn same_api_as_the_single_threaded_node() {
+        let root = SyncNode::new(1);
+        let a = SyncNode::new(2);
+        let b = SyncNode::new(3);
+        root.add_child(Arc::clone(&a));
+        root.add_child(Arc::clone(&b));
+        a.add_child(SyncNode::new(4));
+
+        assert_eq!(
+            values(&root.preorder().collect::<Vec<_>>()),
+            vec![1, 2, 4, 3]
+        );
+        assert_eq!(
+            values(&root.postorder().collect::<Vec<_>>()),
+            vec![4, 2, 3, 1]
+        );
+        assert_eq!(
+            values(&root.breadth_first().collect::<Vec<_>>()),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(values(&root.leaves()), vec![4, 3]);
+        assert_eq!(values(&root.find_all(|v| v % 2 == 0)), vec![2, 4]);
+        assert_eq!((root.height(), root.count()), (2, 4));
+
+        let four = root.find(|v| *v == 4).unwrap();
+        assert_eq!(values(&four.path_from_root()), vec![1, 2, 4]);
+        assert_eq!(four.depth(), 2);
+
+        assert_eq!(root.move_to(&four), Err(TreeError::WouldCreateCycle));
+        four.move_to(&b).unwrap();
+        assert_eq!(values(&b.children()), vec![4]);
+        assert!(a.children().is_empty());
+
+        root.insert_child(0, Arc::clone(&b)).unwrap();
+        assert_eq!(values(&root.children()), vec![3, 2]);
+        assert!(root.remove_child(&a));
+        assert!(a.parent().is_none());
+        b.detach();
+        assert!(root.children().is_empty());
+        assert_eq!(Arc::weak_count(&root), 0);
+    }
+
+    #[test]
+    fn trees_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Arc<SyncNode<i32>>>();
+    }
+
+    #[test]
+    fn concurrent_add_children_to_one_parent() {
+        let root = SyncNode::new(0);
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let root = Arc::clone(&root);
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        root.add_child(SyncNode::new(t * 1000 + i));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(root.children().len(), 1600);
+        assert_consistent(&root, 1601);
+    }
+
+    #[test]
+    fn concurrent_moves_keep_the_tree_consistent() {
+        const NODES: usize = 64;
+        let root = SyncNode::new(0);
+        let nodes: Arc<Vec<_>> = Arc::new((1..=NODES).map(SyncNode::new).collect());
+        for node in nodes.iter() {
+            root.add_child(Arc::clone(node));
+        }
+
+        let handles: Vec<_> = (0..8u64)
+            .map(|t| {
+                let root = Arc::clone(&root);
+                let nodes = Arc::clone(&nodes);
+                thread::spawn(move || {
+                    let mut seed = 0x9e37_79b9_7f4a_7c15 ^ (t + 1);
+                    let mut pick = move || {
+                        seed ^= seed << 13;
+                        seed ^= seed >> 7;
+                        seed ^= seed << 17;
+                        (seed % NODES as u64) as usize
+                    };
+                    for _ in 0..2000 {
+                        let node = &nodes[pick()];
+                        let target = &nodes[pick()];
+                        match pick() % 4 {
+                            // Cycles are rejected, any other move must succeed.
+                            0 | 1 => match node.move_to(target) {
+                                Ok(()) | Err(TreeError::WouldCreateCycle) => {}
+                                Err(err) => panic!("{err}"),
+                            },
+                            2 => node.move_to(&root).unwrap(),
+                            _ => {
+                                // Readers run alongside the writers.
+                                let _ = root.count();
+                                let _ = node.path_from_root();
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_consistent(&root, NODES + 1);
+        for node in nodes.iter() {
+            // Our handle plus exactly one parent's `children`.
+            assert_eq!(Arc::strong_count(node), 2);
+        }
+    }
+
+    #[test]
+    fn concurrent_moves_between_trees() {
+        const NODES: usize = 64;
+        let roots = Arc::new([SyncNode::new(0), SyncNode::new(1000)]);
+        let nodes: Arc<Vec<_>> = Arc::new((1..=NODES).map(SyncNode::new).collect());
+        for (i, node) in nodes.iter().enumerate() {
+            roots[i % 2].add_child(Arc::clone(node));
+        }
+
+        let handles: Vec<_> = (0..8u64)
+            .map(|t| {
+                let roots = Arc::clone(&roots);
+                let nodes = Arc::clone(&nodes);
+                thread::spawn(move || {
+                    let mut seed = 0x2545_f491_4f6c_dd1d ^ (t + 1);
+                    let mut pick = move || {
+                        seed ^= seed << 13;
+                        seed ^= seed >> 7;
+                        seed ^= seed << 17;
+                        (seed % NODES as u64) as usize
+                    };
+                    for _ in 0..2000 {
+                        let node = &nodes[pick()];
+                        match pick() % 3 {
+                            // Often lands in the other tree, taking the subtree along.
+                            0 => match node.move_to(&nodes[pick()]) {
+                                Ok(()) | Err(TreeError::WouldCreateCycle) => {}
+                                Err(err) => panic!("{err}"),
+                            },
+                            1 => node.move_to(&roots[pick() % 2]).unwrap(),
+                            _ => {
+                                // A root for a moment; the next move picks it up again.
+                                node.detach();
+                                node.move_to(&roots[pick() % 2]).unwrap();
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let (left, right) = (roots[0].count(), roots[1].count());
+        assert_eq!(left + right, NODES + 2);
+        assert_consistent(&roots[0], left);
+        assert_consistent(&roots[1], right);
+        for node in nodes.iter() {
+            assert_eq!(Arc::strong_count(node), 2);
+        }
+    }
+}
@@ -0,0 +1,585 @@
+//! Text formats for `weakt::Node` trees.
+//!
+//! The indented format puts one value per line, two spaces deeper than its parent:
+//!
+//! ```text
+//! 5
+//!   3
+//!   7
+//!     8
+//! ```
+//!
+//! The S-expression format writes the same tree as `(5 (3) (7 (8)))`.
+//!
+//! In both, a value that is empty, starts with `"`, or contains whitespace or a
+//! parenthesis is written in double quotes, with `\"`, `\\`, `\n`, `\r` and `\t`
+//! escapes: `("a b" ("") ("say \"hi\""))`.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::weakt::Node;
+
+const INDENT: usize = 2;
+
+pub fn to_indented<T: fmt::Display>(root: &Rc<Node<T>>) -> String {
+    let mut out = String::new();
+    let mut stack = vec![(Rc::clone(root), 0)];
+    while let Some((node, depth)) = stack.pop() {
+        out.push_str(&" ".repeat(depth * INDENT));
+        write_value(&mut out, &node.value().to_string());
+        out.push('\n');
+        stack.extend(
+            node.children()
+                .into_iter()
+                .rev()
+                .map(|child| (child, depth + 1)),
+        );
+    }
+    out
+}
+
+pub fn to_sexpr<T: fmt::Display>(root: &Rc<Node<T>>) -> String {
+    enum Step<T> {
+        Open(Rc<Node<T>>),
+        Close,
+    }
+
+    let mut out = String::new();
+    let mut stack = vec![Step::Open(Rc::clone(root))];
+    while let Some(step) = stack.pop() {
+        match step {
+            Step::Open(node) => {
+                if out.ends_with(')') {
+                    out.push(' ');
+                }
+                out.push('(');
+                write_value(&mut out, &node.value().to_string());
+                let children = node.children();
+                if !children.is_empty() {
+                    out.push(' ');
+                }
+                stack.push(Step::Close);
+                stack.extend(children.into_iter().rev().map(Step::Open));
+            }
+            Step::Close => out.push(')'),
+        }
+    }
+    out
+}
+
+pub fn parse_indented<T: FromStr>(text: &str) -> Result<Rc<Node<T>>, ParseError> {
+    let mut root: Option<Rc<Node<T>>> = None;
+    // The most recent node at each depth, from the root down.
+    let mut path: Vec<Rc<Node<T>>> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let value = line.trim_start_matches(' ');
+        if value.trim().is_empty() {
+            continue;
+        }
+
+        let indent = line.len() - value.len();
+        let error = |column, kind| ParseError {
+            line: line_number,
+            column,
+            kind,
+        };
+        if value.starts_with(char::is_whitespace) {
+            return Err(error(indent + 1, ParseErrorKind::BadIndent));
+        }
+        if indent % INDENT != 0 || indent / INDENT > path.len() {
+            return Err(error(indent + 1, ParseErrorKind::BadIndent));
+        }
+
+        let depth = indent / INDENT;
+        if depth == 0 && root.is_some() {
+            return Err(error(1, ParseErrorKind::MultipleRoots));
+        }
+
+        let value = if value.starts_with('"') {
+            let mut cursor = Cursor::at(value, line_number, indent + 1);
+            let quoted = cursor.quoted()?;
+            cursor.skip_whitespace();
+            if let Some(c) = cursor.peek() {
+                let (line, column) = cursor.position();
+                return Err(ParseError {
+                    line,
+                    column,
+                    kind: ParseErrorKind::UnexpectedChar(c),
+                });
+            }
+            Cow::Owned(quoted)
+        } else {
+            Cow::Borrowed(value.trim_end())
+        };
+        let node = Node::new(parse_value(&value, line_number, indent + 1)?);
+        path.truncate(depth);
+        match path.last() {
+            Some(parent) => parent.add_child(Rc::clone(&node)),
+            None => root = Some(Rc::clone(&node)),
+        }
+        path.push(node);
+    }
+
+    root.ok_or(ParseError {
+        line: text.lines().count().max(1),
+        column: 1,
+        kind: ParseErrorKind::Empty,
+    })
+}
+
+pub fn parse_sexpr<T: FromStr>(text: &str) -> Result<Rc<Node<T>>, ParseError> {
+    let mut cursor = Cursor::new(text);
+    let mut stack: Vec<Rc<Node<T>>> = Vec::new();
+
+    loop {
+        cursor.skip_whitespace();
+        let (line, column) = cursor.position();
+        let error = |kind| ParseError { line, column, kind };
+
+        match cursor.peek() {
+            Some('(') => {
+                cursor.next();
+                let (line, column) = cursor.position();
+                let atom = if cursor.peek() == Some('"') {
+                    Cow::Owned(cursor.quoted()?)
+                } else {
+                    let atom = cursor.atom();
+                    if atom.is_empty() {
+                        let kind = match cursor.peek() {
+                            Some(c) => ParseErrorKind::UnexpectedChar(c),
+                            None => ParseErrorKind::UnexpectedEnd,
+                        };
+                        return Err(ParseError { line, column, kind });
+                    }
+                    Cow::Borrowed(atom)
+                };
+
+                let node = Node::new(parse_value(&atom, line, column)?);
+                if let Some(parent) = stack.last() {
+                    parent.add_child(Rc::clone(&node));
+                }
+                stack.push(node);
+            }
+            Some(')') if !stack.is_empty() => {
+                cursor.next();
+                let node = stack.pop().unwrap();
+                if stack.is_empty() {
+                    cursor.skip_whitespace();
+                    let (line, column) = cursor.position();
+                    return match cursor.peek() {
+                        None => Ok(node),
+                        Some(c) => Err(ParseError {
+                            line,
+                            column,
+                            kind: ParseErrorKind::UnexpectedChar(c),
+                        }),
+                    };
+                }
+            }
+            Some(c) => return Err(error(ParseErrorKind::UnexpectedChar(c))),
+            None if stack.is_empty() => return Err(error(ParseErrorKind::Empty)),
+            None => return Err(error(ParseErrorKind::UnexpectedEnd)),
+        }
+    }
+}
+
+fn write_value(out: &mut String, value: &str) {
+    let plain = !value.is_empty()
+        && !value.starts_with('"')
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '(' || c == ')');
+    if plain {
+        out.push_str(value);
+        return;
+    }
+
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn parse_value<T: FromStr>(text: &str, line: usize, column: usize) -> Result<T, ParseError> {
+    text.parse().map_err(|_| ParseError {
+        line,
+        column,
+        kind: ParseErrorKind::InvalidValue(text.to_string()),
+    })
+}
+
+/// Where parsing failed; `line` and `column` count from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    Empty,
+    UnexpectedEnd,
+    UnexpectedChar(char),
+    InvalidValue(String),
+    /// A quoted value with no closing quote on its line (indented) or before the
+    /// end (S-expression).
+    UnterminatedQuote,
+    InvalidEscape(char),
+    /// Not a multiple of two spaces, a tab, or more than one level deeper than
+    /// the line before.
+    BadIndent,
+    MultipleRoots,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        match &self.kind {
+            ParseErrorKind::Empty => write!(f, "no tree found"),
+            ParseErrorKind::UnexpectedEnd => write!(f, "unexpected end of input"),
+            ParseErrorKind::UnexpectedChar(c) => write!(f, "unexpected character {c:?}"),
+            ParseErrorKind::InvalidValue(value) => write!(f, "invalid value {value:?}"),
+            ParseErrorKind::UnterminatedQuote => write!(f, "quoted value is never closed"),
+            ParseErrorKind::InvalidEscape(c) => write!(f, "invalid escape \\{c}"),
+            ParseErrorKind::BadIndent => write!(f, "bad indentation"),
+            ParseErrorKind::MultipleRoots => write!(f, "a tree has only one root"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Cursor<'a> {
+    rest: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(text: &'a str) -> Cursor<'a> {
+        Cursor::at(text, 1, 1)
+    }
+
+    // For text that starts somewhere other than the beginning of the input.
+    fn at(text: &'a str, line: usize, column: usize) -> Cursor<'a> {
+        Cursor {
+            rest: text,
+            line,
+            column,
+        }
+    }
+
+    fn position(&self) -> (usize, usize) {
+        (self.line, self.column)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    fn atom(&mut self) -> &'a str {
+        let start = self.rest;
+        let mut len = 0;
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == '(' || c == ')' {
+                break;
+            }
+            len += c.len_utf8();
+            self.next();
+        }
+        &start[..len]
+    }
+
+    // Reads a quoted value, starting at its opening quote, and undoes the escapes.
+    fn quoted(&mut self) -> Result<String, ParseError> {
+        let (line, column) = self.position();
+        let unterminated = || ParseError {
+            line,
+            column,
+            kind: ParseErrorKind::UnterminatedQuote,
+        };
+        self.next();
+
+        let mut value = String::new();
+        loop {
+            let (line, column) = self.position();
+            match self.next().ok_or_else(unterminated)? {
+                '"' => return Ok(value),
+                '\\' => match self.next().ok_or_else(unterminated)? {
+                    '"' => value.push('"'),
+                    '\\' => value.push('\\'),
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    c => {
+                        return Err(ParseError {
+                            line,
+                            column,
+                            kind: ParseErrorKind::InvalidEscape(c),
+                        });
+                    }
+                },
+                c => value.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ParseError, ParseErrorKind, parse_indented, parse_sexpr, to_indented, to_sexpr};
+    use crate::weakt::Node;
+    use std::rc::Rc;
+
+    fn sample_tree() -> Rc<Node<i32>> {
+        let root = Node::new(5);
+        let seven = Node::new(7);
+        root.add_child(Node::new(3));
+        root.add_child(Rc::clone(&seven));
+        seven.add_child(Node::new(8));
+        root
+    }
+
+    // Same values in the same shape, and every parent link points the right way.
+    fn assert_same_tree<T: PartialEq + std::fmt::Debug>(a: &Rc<Node<T>>, b: &Rc<Node<T>>) {
+        assert!(b.parent().is_none());
+        let mut stack = vec![(Rc::clone(a), Rc::clone(b))];
+        while let Some((a, b)) = stack.pop() {
+            assert_eq!(a.value(), b.value());
+            let (a_children, b_children) = (a.children(), b.children());
+            assert_eq!(a_children.len(), b_children.len());
+            for child in &b_children {
+                assert!(Rc::ptr_eq(&child.parent().unwrap(), &b));
+            }
+            stack.extend(a_children.into_iter().zip(b_children));
+        }
+    }
+
+    #[test]
+    fn print_both_formats() {
+        let tree = sample_tree();
+        assert_eq!(to_sexpr(&tree), "(5 (3) (7 (8)))");
+        assert_eq!(to_indented(&tree), "5\n  3\n  7\n    8\n");
+        assert_eq!(to_sexpr(&Node::new(1)), "(1)");
+    }
+
+    #[test]
+    fn parse_both_formats() {
+        let tree = sample_tree();
+        assert_same_tree(&tree, &parse_sexpr("(5 (3) (7 (8)))").unwrap());
+        assert_same_tree(&tree, &parse_sexpr(" (5\n  (3)\n  (7 (8) ) )\n").unwrap());
+        assert_same_tree(&tree, &parse_indented("5\n  3\n\n  7\n    8").unwrap());
+
+        let words: Rc<Node<String>> = parse_sexpr("(root (a) (b))").unwrap();
+        assert_eq!(to_indented(&words), "root\n  a\n  b\n");
+    }
+
+    #[test]
+    fn sexpr_errors_carry_line_and_column() {
+        let err = |text| parse_sexpr::<i32>(text).unwrap_err();
+
+        assert_eq!(
+            err("(5 (3) (x))"),
+            ParseError {
+                line: 1,
+                column: 9,
+                kind: ParseErrorKind::InvalidValue(String::from("x")),
+            }
+        );
+        assert_eq!(
+            err("(5\n  (3"),
+            ParseError {
+                line: 2,
+                column: 5,
+                kind: ParseErrorKind::UnexpectedEnd,
+            }
+        );
+        assert_eq!(
+            err("(5) (6)"),
+            ParseError {
+                line: 1,
+                column: 5,
+                kind: ParseErrorKind::UnexpectedChar('('),
+            }
+        );
+        assert_eq!(err("(5 ())").kind, ParseErrorKind::UnexpectedChar(')'));
+        assert_eq!(err("5").kind, ParseErrorKind::UnexpectedChar('5'));
+        assert_eq!(err("  ").kind, ParseErrorKind::Empty);
+        assert_eq!(
+            err("(5 (3) (x))").to_string(),
+            "line 1, column 9: invalid value \"x\""
+        );
+    }
+
+    #[test]
+    fn indented_errors_carry_line_and_column() {
+        let err = |text| parse_indented::<i32>(text).unwrap_err();
+
+        assert_eq!(
+            err("5\n  3\n      8"),
+            ParseError {
+                line: 3,
+                column: 7,
+                kind: ParseErrorKind::BadIndent,
+            }
+        );
+        assert_eq!(err("5\n 3").kind, ParseErrorKind::BadIndent);
+        assert_eq!(err("5\n\t3").kind, ParseErrorKind::BadIndent);
+        assert_eq!(
+            err("5\n  3\n6"),
+            ParseError {
+                line: 3,
+                column: 1,
+                kind: ParseErrorKind::MultipleRoots,
+            }
+        );
+        assert_eq!(
+            err("5\n  three"),
+            ParseError {
+                line: 2,
+                column: 3,
+                kind: ParseErrorKind::InvalidValue(String::from("three")),
+            }
+        );
+        assert_eq!(err("\n\n").kind, ParseErrorKind::Empty);
+    }
+
+    // A small xorshift generator, so the round-trip tests are repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn random_tree<T>(rng: &mut Rng, mut value: impl FnMut(&mut Rng) -> T) -> Rc<Node<T>> {
+        let size = 1 + rng.below(40);
+        let mut nodes = vec![Node::new(value(rng))];
+        for _ in 1..size {
+            let parent = Rc::clone(&nodes[rng.below(nodes.len())]);
+            let child = Node::new(value(rng));
+            parent.add_child(Rc::clone(&child));
+            nodes.push(child);
+        }
+        Rc::clone(&nodes[0])
+    }
+
+    // Mostly the characters that need quoting, and sometimes nothing at all.
+    fn random_string(rng: &mut Rng) -> String {
+        const CHARS: [char; 12] = [
+            'a', 'Z', '7', ' ', '(', ')', '"', '\\', '\n', '\t', '\r', 'é',
+        ];
+        let len = rng.below(6);
+        (0..len).map(|_| CHARS[rng.below(CHARS.len())]).collect()
+    }
+
+    #[test]
+    fn round_trip_random_trees() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..300 {
+            let tree = random_tree(&mut rng, |rng| rng.next() as i32 % 1000);
+            assert_same_tree(&tree, &parse_sexpr(&to_sexpr(&tree)).unwrap());
+            assert_same_tree(&tree, &parse_indented(&to_indented(&tree)).unwrap());
+
+            let tree = random_tree(&mut rng, random_string);
+            assert_same_tree(&tree, &parse_sexpr(&to_sexpr(&tree)).unwrap());
+            assert_same_tree(&tree, &parse_indented(&to_indented(&tree)).unwrap());
+        }
+    }
+
+    #[test]
+    fn values_that_need_quotes() {
+        let root = Node::new(String::from("a b"));
+        root.add_child(Node::new(String::new()));
+        root.add_child(Node::new(String::from("say \"hi\"\n")));
+        root.add_child(Node::new(String::from(" (x)")));
+
+        assert_eq!(to_sexpr(&root), r#"("a b" ("") ("say \"hi\"\n") (" (x)"))"#);
+        assert_eq!(
+            to_indented(&root),
+            "\"a b\"\n  \"\"\n  \"say \\\"hi\\\"\\n\"\n  \" (x)\"\n"
+        );
+        assert_same_tree(&root, &parse_sexpr(&to_sexpr(&root)).unwrap());
+        assert_same_tree(&root, &parse_indented(&to_indented(&root)).unwrap());
+    }
+
+    #[test]
+    fn quote_errors() {
+        let sexpr = |text| parse_sexpr::<String>(text).unwrap_err();
+        let indented = |text| parse_indented::<String>(text).unwrap_err();
+
+        assert_eq!(
+            sexpr("(a (\"b c))"),
+            ParseError {
+                line: 1,
+                column: 5,
+                kind: ParseErrorKind::UnterminatedQuote,
+            }
+        );
+        assert_eq!(
+            sexpr("(\"a\\q\")"),
+            ParseError {
+                line: 1,
+                column: 4,
+                kind: ParseErrorKind::InvalidEscape('q'),
+            }
+        );
+        assert_eq!(sexpr("(\"a\"b)").kind, ParseErrorKind::UnexpectedChar('b'));
+        assert_eq!(
+            indented("a\n  \"b"),
+            ParseError {
+                line: 2,
+                column: 3,
+                kind: ParseErrorKind::UnterminatedQuote,
+            }
+        );
+        assert_eq!(
+            indented("a\n  \"b\" c"),
+            ParseError {
+                line: 2,
+                column: 7,
+                kind: ParseErrorKind::UnexpectedChar('c'),
+            }
+        );
+    }
+}